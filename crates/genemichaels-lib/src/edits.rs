@@ -0,0 +1,104 @@
+use {
+    serde::Serialize,
+    similar::{
+        capture_diff_slices,
+        Algorithm,
+        DiffOp,
+    },
+    std::ops::Range,
+};
+
+/// Replace a byte range of the original source with new text.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TextEdit {
+    pub range: Range<usize>,
+    pub replacement: String,
+}
+
+/// Split text into runs of identifier characters, runs of whitespace, and
+/// individual other characters so diffs don't split words.
+fn chunks(text: &str) -> Vec<&str> {
+    #[derive(PartialEq)]
+    enum Class {
+        Word,
+        Space,
+        Other,
+    }
+
+    fn class(c: char) -> Class {
+        if c.is_alphanumeric() || c == '_' {
+            Class::Word
+        } else if c.is_whitespace() {
+            Class::Space
+        } else {
+            Class::Other
+        }
+    }
+
+    let mut out = vec![];
+    let mut start = 0usize;
+    let mut prev = None;
+    for (i, c) in text.char_indices() {
+        let c_class = class(c);
+        if let Some(p) = prev.take() {
+            if p != c_class || c_class == Class::Other {
+                out.push(&text[start .. i]);
+                start = i;
+            }
+        }
+        prev = Some(c_class);
+    }
+    if start < text.len() {
+        out.push(&text[start..]);
+    }
+    out
+}
+
+/// Compute a small set of edits that turn `source` into `rendered`, ordered by
+/// position and non-overlapping. Ranges are byte offsets in `source`.
+pub fn text_edits(source: &str, rendered: &str) -> Vec<TextEdit> {
+    let a = chunks(source);
+    let b = chunks(rendered);
+    let mut a_offsets = Vec::with_capacity(a.len() + 1);
+    let mut at = 0usize;
+    for c in &a {
+        a_offsets.push(at);
+        at += c.len();
+    }
+    a_offsets.push(at);
+    let mut b_offsets = Vec::with_capacity(b.len() + 1);
+    let mut at = 0usize;
+    for c in &b {
+        b_offsets.push(at);
+        at += c.len();
+    }
+    b_offsets.push(at);
+    let mut edits: Vec<TextEdit> = vec![];
+    for op in capture_diff_slices(Algorithm::Myers, &a, &b) {
+        let (old, new) = match op {
+            DiffOp::Equal { .. } => continue,
+            DiffOp::Delete { old_index, old_len, new_index } => (old_index .. old_index + old_len, new_index .. new_index),
+            DiffOp::Insert { old_index, new_index, new_len } => (old_index .. old_index, new_index .. new_index + new_len),
+            DiffOp::Replace { old_index, old_len, new_index, new_len } => (
+                old_index .. old_index + old_len,
+                new_index .. new_index + new_len,
+            ),
+        };
+        let range = a_offsets[old.start] .. a_offsets[old.end];
+        let replacement = &rendered[b_offsets[new.start] .. b_offsets[new.end]];
+
+        // Merge adjacent changes into one edit
+        if let Some(last) = edits.last_mut() {
+            if last.range.end == range.start {
+                last.range.end = range.end;
+                last.replacement.push_str(replacement);
+                continue;
+            }
+        }
+        edits.push(TextEdit {
+            range: range,
+            replacement: replacement.to_string(),
+        });
+    }
+    edits
+}
@@ -13,26 +13,62 @@ use {
     proc_macro2::{
         Ident,
         LineColumn,
+        TokenStream,
+        TokenTree,
+        Delimiter,
     },
     quote::ToTokens,
     serde::{
         Serialize,
         Deserialize,
     },
-    sg_general::append_whitespace,
+    sg_general::{
+        append_statement_list_raw,
+        append_whitespace,
+        space_inline_comments,
+    },
+    similar::{
+        capture_diff_slices,
+        Algorithm,
+        DiffOp,
+    },
     std::{
-        collections::BTreeMap,
+        collections::{
+            BTreeMap,
+            BTreeSet,
+        },
         cell::RefCell,
+        ops::Range,
         rc::Rc,
     },
-    syn::File,
+    syn::{
+        parse::{
+            ParseStream,
+            Parser,
+        },
+        Block,
+        Expr,
+        File,
+        ImplItem,
+        Item,
+        Pat,
+        Type,
+    },
 };
 pub use whitespace::{
     format_md,
+    format_md_styled,
+    looks_preformatted,
+    intra_doc_link_targets,
     HashLineColumn,
 };
+pub use edits::{
+    text_edits,
+    TextEdit,
+};
 
 pub(crate) mod whitespace;
+pub(crate) mod edits;
 pub(crate) mod sg_expr;
 pub(crate) mod sg_general;
 pub(crate) mod sg_pat;
@@ -60,6 +96,11 @@ pub struct Comment {
 pub enum WhitespaceMode {
     BlankLines(usize),
     Comment(Comment),
+    // A `//` comment at the end of a line, kept there rather than moved before the
+    // line. The loc is the last token on the line.
+    LineEndComment(Comment),
+    // A single-line `/* */` comment kept inline before the token at loc.
+    InlineBlockComment(Comment),
 }
 
 #[derive(Debug)]
@@ -102,6 +143,9 @@ pub(crate) enum SegmentContent {
     Text(String),
     Whitespace((Alignment, Vec<Whitespace>)),
     Break(Alignment, bool),
+    // Rendered after the preceding segment on the same line if it fits, otherwise on
+    // its own line before. Always followed by a break.
+    LineEndComment(Alignment, String),
 }
 
 pub(crate) struct Segment {
@@ -126,6 +170,20 @@ pub struct MakeSegsState {
     segs: Vec<Segment>,
     whitespaces: BTreeMap<HashLineColumn, Vec<Whitespace>>,
     config: FormatConfig,
+    // The source the AST was parsed from, if available. Span byte ranges index into
+    // this.
+    source: Option<String>,
+    // Comments that stay at the end of the line, keyed by the last token on the
+    // line. Added after the preceding text when whitespace after the token is
+    // appended.
+    line_end_comments: BTreeMap<HashLineColumn, Comment>,
+    // Starts of top level `Item::Verbatim` that couldn't be parsed during recovery,
+    // written as-is from the source
+    unparsed: BTreeSet<HashLineColumn>,
+    // Write `#[doc]` attributes as `///` comments
+    doc_attrs_as_comments: bool,
+    // Inline `/* */` comment segments, spaced once the text around them is known
+    inline_comments: Vec<SegmentIdx>,
 }
 
 pub(crate) fn check_split_brace_threshold(out: &MakeSegsState, count: usize) -> bool {
@@ -144,6 +202,7 @@ pub(crate) fn line_length(out: &MakeSegsState, lines: &Lines, line_i: LineIdx) -
                 }
             },
             SegmentContent::Whitespace(_) => { },
+            SegmentContent::LineEndComment(_, _) => { },
         };
     }
     len
@@ -478,8 +537,172 @@ fn render_indent(config: &FormatConfig, current_indent: IndentLevel) -> String {
     }
 }
 
-#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
-#[serde(default)]
+/// How to format the body of a specific macro, overriding the default heuristics.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MacroStrategy {
+    /// Leave the macro body exactly as written, including comments.
+    Verbatim,
+    /// Format the body as a comma separated list of expressions, like function call
+    /// arguments.
+    Exprs,
+    /// Format the body as a list of statements, like the inside of a block.
+    Block,
+    /// Format the body as a list of items, like the inside of a module.
+    Items,
+    /// Don't try to parse the body as Rust, just join the tokens.
+    Freeform,
+}
+
+/// Line endings to use in formatted output.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NewlineStyle {
+    /// Use whichever of `\n` and `\r\n` is more common in the input.
+    Auto,
+    Lf,
+    Crlf,
+    /// `\r\n` on Windows, `\n` elsewhere.
+    Native,
+}
+
+/// How to wrap text when reformatting comments.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommentWrap {
+    /// Join lines within paragraphs then wrap them to fit the width.
+    Reflow,
+    /// Keep existing line breaks, only splitting lines that are too long.
+    SplitOnly,
+}
+
+/// Heuristics for spotting comments that are preformatted (diagrams, tables
+/// without markdown syntax, etc) and would be mangled by formatting them as
+/// markdown. These are left as-is, with a warning.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PreformattedDetection {
+    /// Lines with box-drawing characters, or ASCII boxes and table rows (`+---+`, `|
+    /// a | b |`).
+    pub box_drawing: bool,
+    /// Lines with text aligned in columns after runs of spaces.
+    pub aligned_columns: bool,
+    /// Lines where at least this fraction of the non-whitespace characters are
+    /// punctuation or symbols.
+    pub punctuation_ratio: Option<f64>,
+    /// How many lines need to match one of the above for a comment to be considered
+    /// preformatted.
+    pub min_lines: usize,
+}
+
+impl Default for PreformattedDetection {
+    fn default() -> Self {
+        Self {
+            box_drawing: true,
+            aligned_columns: true,
+            punctuation_ratio: Some(0.6),
+            min_lines: 2,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+pub enum MarkdownBullet {
+    #[serde(rename = "*")]
+    Asterisk,
+    #[serde(rename = "-")]
+    Dash,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+pub enum MarkdownEmphasis {
+    #[serde(rename = "_")]
+    Underscore,
+    #[serde(rename = "*")]
+    Asterisk,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+pub enum MarkdownStrong {
+    #[serde(rename = "**")]
+    Asterisks,
+    #[serde(rename = "__")]
+    Underscores,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkdownOrderedList {
+    /// Number items counting up from the first item's number.
+    Sequential,
+    /// Number every item `1.`, so items can be added or moved without renumbering.
+    Ones,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkdownHeadings {
+    /// Write all headings as `#` headings.
+    Atx,
+    /// Keep underlined (setext) headings underlined.
+    Keep,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+pub enum MarkdownCodeFence {
+    #[serde(rename = "`")]
+    Backticks,
+    #[serde(rename = "~")]
+    Tildes,
+}
+
+/// How to write markdown syntax that has multiple equivalent forms when
+/// reformatting comments.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarkdownStyle {
+    pub bullet: MarkdownBullet,
+    pub emphasis: MarkdownEmphasis,
+    pub strong: MarkdownStrong,
+    pub ordered_list: MarkdownOrderedList,
+    pub headings: MarkdownHeadings,
+    pub code_fence: MarkdownCodeFence,
+    /// Minimum code fence length. Fences are made longer if the code contains a fence.
+    pub code_fence_length: usize,
+}
+
+impl Default for MarkdownStyle {
+    fn default() -> Self {
+        Self {
+            bullet: MarkdownBullet::Asterisk,
+            emphasis: MarkdownEmphasis::Underscore,
+            strong: MarkdownStrong::Asterisks,
+            ordered_list: MarkdownOrderedList::Sequential,
+            headings: MarkdownHeadings::Atx,
+            code_fence: MarkdownCodeFence::Backticks,
+            code_fence_length: 3,
+        }
+    }
+}
+
+/// Options for `format_md_styled`.
+#[derive(Debug, Clone)]
+pub struct MarkdownOptions {
+    pub style: MarkdownStyle,
+    pub wrap: CommentWrap,
+}
+
+impl Default for MarkdownOptions {
+    fn default() -> Self {
+        Self {
+            style: MarkdownStyle::default(),
+            wrap: CommentWrap::Reflow,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct FormatConfig {
     pub max_width: usize,
     pub root_splits: bool,
@@ -488,11 +711,40 @@ pub struct FormatConfig {
     pub split_where: bool,
     pub comment_width: Option<usize>,
     pub comment_errors_fatal: bool,
+    /// Whether to join lines in comment paragraphs before wrapping.
+    pub comment_wrap: CommentWrap,
     pub keep_max_blank_lines: usize,
     pub indent_spaces: usize,
     /// Indent with spaces or tabs.
     pub indent_unit: IndentUnit,
+    /// Line endings for formatted source.
+    pub newline_style: NewlineStyle,
     pub explicit_markdown_comments: bool,
+    /// Markdown syntax style for reformatted comments.
+    pub markdown: MarkdownStyle,
+    /// Leave comments that look preformatted as-is rather than formatting them as
+    /// markdown. `None` (the default) disables detection.
+    pub detect_preformatted: Option<PreformattedDetection>,
+    /// Warn about rustdoc intra-doc links in doc comments whose targets aren't items
+    /// in the same file (or imported there). Only applies to whole files.
+    pub check_intra_doc_links: bool,
+    /// Keep `//` comments at the end of a line where they are instead of moving them
+    /// to before the line, if the line fits within `max_width`.
+    pub keep_line_end_comments: bool,
+    /// Keep single-line `/* */` comments inline before the following token rather
+    /// than converting them to `//` comments on their own lines.
+    pub keep_inline_block_comments: bool,
+    /// Per-macro formatting strategies. Keys are either a full macro path as written
+    /// (`sqlx::query`) or a bare macro name (`html`), which matches regardless of the
+    /// path prefix.
+    pub macros: BTreeMap<String, MacroStrategy>,
+    /// Fill in `FormatRes::position_map`. This lexes the formatted output again, and
+    /// proc_macro2 keeps the text of everything it lexes until the thread exits, so
+    /// each format with this on adds the output size to memory use. Leave it off in
+    /// long running processes unless the map is needed. Can't be set from config
+    /// files.
+    #[serde(skip)]
+    pub position_map: bool,
 }
 
 impl Default for FormatConfig {
@@ -505,10 +757,19 @@ impl Default for FormatConfig {
             split_where: true,
             comment_width: Some(80usize),
             comment_errors_fatal: false,
+            comment_wrap: CommentWrap::Reflow,
             keep_max_blank_lines: 0,
             indent_spaces: 4,
             indent_unit: IndentUnit::Spaces,
+            newline_style: NewlineStyle::Auto,
             explicit_markdown_comments: false,
+            markdown: MarkdownStyle::default(),
+            detect_preformatted: None,
+            check_intra_doc_links: false,
+            keep_line_end_comments: false,
+            keep_inline_block_comments: false,
+            macros: BTreeMap::new(),
+            position_map: false,
         }
     }
 }
@@ -517,11 +778,175 @@ pub struct FormatRes {
     pub rendered: String,
     pub lost_comments: BTreeMap<HashLineColumn, Vec<Whitespace>>,
     pub warnings: Vec<Error>,
+    /// Where each input token ended up in the output, ordered by input position.
+    /// Only available with `FormatConfig::position_map` when formatting from source
+    /// (`format_str`, `format_fragment_str`, etc.). Tokens added or removed by
+    /// formatting (ex: trailing commas) have no mapping.
+    pub position_map: Vec<PositionMapping>,
+    /// How many items were left as-is because they couldn't be parsed (only with
+    /// `format_str_recover`). If non-zero the output may not parse either.
+    pub unparsed_items: usize,
+}
+
+/// Byte ranges of a token in the formatting input and output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionMapping {
+    pub input: Range<usize>,
+    pub output: Range<usize>,
+}
+
+impl FormatRes {
+    /// Translate a byte offset in the input (ex: a cursor) to the corresponding
+    /// offset in the output. Offsets within a token stay at the same place in the
+    /// token, offsets between tokens go to the end of the preceding token.
+    pub fn map_offset(&self, input_offset: usize) -> usize {
+        let i = self.position_map.partition_point(|m| m.input.start <= input_offset);
+        if i == 0 {
+            return 0;
+        }
+        let m = &self.position_map[i - 1];
+        (m.output.start + (input_offset - m.input.start)).min(m.output.end)
+    }
+
+    /// Edits to turn `source` (the text that was formatted) into the formatted text,
+    /// for applying formatting without replacing the whole document.
+    pub fn edits(&self, source: &str) -> Vec<TextEdit> {
+        text_edits(source, &self.rendered)
+    }
 }
 
-pub use whitespace::extract_whitespaces;
+/// Match up tokens in the input and formatted output. Tokens are mostly the same
+/// in both, except where formatting added or removed some like commas, so this
+/// diffs the two token sequences and maps the tokens they have in common. Doc
+/// attributes are skipped on both sides, since in the input doc comments were
+/// extracted as comments but in the output they lex as `#[doc]` tokens.
+fn map_positions(input: TokenStream, rendered: &str, output_offset: usize) -> Vec<PositionMapping> {
+    fn is_doc_attr(t: &TokenTree) -> bool {
+        let TokenTree::Group(g) = t else {
+            return false;
+        };
+        g.delimiter() == Delimiter::Bracket &&
+            matches!(g.stream().into_iter().next(), Some(TokenTree::Ident(i)) if i == "doc")
+    }
+
+    fn flatten(ts: TokenStream, out: &mut Vec<(String, Range<usize>)>) {
+        let trees = ts.into_iter().collect::<Vec<_>>();
+        let mut i = 0usize;
+        while i < trees.len() {
+            let t = &trees[i];
+            i += 1;
+            if matches!(t, TokenTree::Punct(p) if p.as_char() == '#') {
+                let bang = matches!(trees.get(i), Some(TokenTree::Punct(p)) if p.as_char() == '!');
+                let attr_at = i + bang as usize;
+                if trees.get(attr_at).is_some_and(is_doc_attr) {
+                    i = attr_at + 1;
+                    continue;
+                }
+            }
+            match t {
+                TokenTree::Group(g) => {
+                    let (open, close) = match g.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => {
+                            flatten(g.stream(), out);
+                            continue;
+                        },
+                    };
+                    out.push((open.to_string(), g.span_open().byte_range()));
+                    flatten(g.stream(), out);
+                    out.push((close.to_string(), g.span_close().byte_range()));
+                },
+                t => out.push((t.to_string(), t.span().byte_range())),
+            }
+        }
+    }
+
+    let Ok(output) = rendered.parse::<TokenStream>() else {
+        return vec![];
+    };
+    let mut a = vec![];
+    flatten(input, &mut a);
+    let mut b = vec![];
+    flatten(output, &mut b);
+    let a_text = a.iter().map(|t| t.0.as_str()).collect::<Vec<_>>();
+    let b_text = b.iter().map(|t| t.0.as_str()).collect::<Vec<_>>();
+    let mut map = vec![];
+    for op in capture_diff_slices(Algorithm::Myers, &a_text, &b_text) {
+        let DiffOp::Equal { old_index, new_index, len } = op else {
+            continue;
+        };
+        for k in 0 .. len {
+            let output = &b[new_index + k].1;
+            map.push(PositionMapping {
+                input: a[old_index + k].1.clone(),
+                output: output.start + output_offset .. output.end + output_offset,
+            });
+        }
+    }
+    map
+}
+
+pub use whitespace::{
+    extract_whitespaces,
+    extract_whitespaces_config,
+};
 
 pub fn format_str(source: &str, config: &FormatConfig) -> Result<FormatRes, loga::Error> {
+    format_str_inner(source, config, false)
+}
+
+/// Like `format_str` but if the source doesn't parse, splits it into top level
+/// items at `;` and `{}` boundaries and formats the items that parse on their own.
+/// Items that don't parse are left as-is (with their comments) and a warning is
+/// added for each. Source that can't be tokenized (ex: unbalanced brackets) is
+/// still an error.
+pub fn format_str_recover(source: &str, config: &FormatConfig) -> Result<FormatRes, loga::Error> {
+    format_str_inner(source, config, true)
+}
+
+fn format_str_inner(source: &str, config: &FormatConfig, recover: bool) -> Result<FormatRes, loga::Error> {
+    // Formatting works with `\n` only and no BOM, so remove them here and restore them
+    // in the output
+    let (bom, source) = match source.strip_prefix('\u{feff}') {
+        Some(s) => ("\u{feff}", s),
+        None => ("", source),
+    };
+    let crlf_count = source.matches("\r\n").count();
+    let crlf = match config.newline_style {
+        NewlineStyle::Auto => crlf_count * 2 > source.matches('\n').count(),
+        NewlineStyle::Lf => false,
+        NewlineStyle::Crlf => true,
+        NewlineStyle::Native => cfg!(windows),
+    };
+    if bom.is_empty() && crlf_count == 0 && !crlf {
+        return format_str_lf(source, config, recover);
+    }
+    let mut input_crs = vec![];
+    for (i, (at, _)) in source.match_indices("\r\n").enumerate() {
+        // Offset of the `\n` after removing previous `\r`s
+        input_crs.push(at - i);
+    }
+    let mut res = format_str_lf(&source.replace("\r\n", "\n"), config, recover)?;
+    let output_crs = if crlf {
+        res.rendered.match_indices('\n').map(|(at, _)| at).collect::<Vec<_>>()
+    } else {
+        vec![]
+    };
+    let restore = |crs: &[usize], offset: usize| bom.len() + offset + crs.partition_point(|at| *at < offset);
+    for m in &mut res.position_map {
+        m.input = restore(&input_crs, m.input.start) .. restore(&input_crs, m.input.end);
+        m.output = restore(&output_crs, m.output.start) .. restore(&output_crs, m.output.end);
+    }
+    if crlf {
+        res.rendered = res.rendered.replace('\n', "\r\n");
+    }
+    res.rendered.insert_str(0, bom);
+    Ok(res)
+}
+
+fn format_str_lf(source: &str, config: &FormatConfig, recover: bool) -> Result<FormatRes, loga::Error> {
     let shebang;
     let shebang_line_off;
     let source1;
@@ -539,48 +964,526 @@ pub fn format_str(source: &str, config: &FormatConfig) -> Result<FormatRes, loga
         shebang_line_off = 0;
     }
     let source = source1;
-    let (whitespaces, tokens) = extract_whitespaces(config.keep_max_blank_lines, source)?;
-    let out =
-        format_ast(
-            syn::parse2::<File>(
-                tokens,
-            ).map_err(
-                |e| loga::err_with(
-                    "Syn error parsing Rust code",
-                    ea!(line = e.span().start().line + shebang_line_off, column = e.span().start().column, err = e),
-                ),
-            )?,
+    let (whitespaces, tokens) = extract_whitespaces_config(config, source)?;
+    let input_tokens = tokens.clone();
+    let mut unparsed = vec![];
+    let ast = match syn::parse2::<File>(tokens.clone()) {
+        Ok(ast) => ast,
+        Err(e) => {
+            if !recover {
+                return Err(
+                    loga::err_with(
+                        "Syn error parsing Rust code",
+                        ea!(
+                            line = e.span().start().line + shebang_line_off,
+                            column = e.span().start().column,
+                            err = e
+                        ),
+                    ),
+                );
+            }
+            parse_recover(tokens, &mut unparsed)
+        },
+    };
+    let mut link_warnings = vec![];
+    if config.check_intra_doc_links {
+        link_warnings = check_intra_doc_links(&ast, &whitespaces, shebang_line_off);
+    }
+    let mut out =
+        format_ast_inner(
+            ast,
             config,
             whitespaces,
+            Some(source),
+            unparsed.iter().map(|(start, _)| HashLineColumn(*start)).collect(),
+            false,
+            shebang_line_off,
         )?;
+    out.unparsed_items = unparsed.len();
+    for (start, e) in unparsed {
+        out
+            .warnings
+            .push(
+                loga::err_with(
+                    "Couldn't parse item, leaving unformatted",
+                    ea!(line = start.line + shebang_line_off, column = start.column, err = e),
+                ),
+            );
+    }
+    out.warnings.extend(link_warnings);
     if let Some(shebang) = shebang {
+        let mut position_map = vec![];
+        if config.position_map {
+            position_map = map_positions(input_tokens, &out.rendered, shebang.len());
+            for m in &mut position_map {
+                m.input = m.input.start + shebang.len() .. m.input.end + shebang.len();
+            }
+        }
         return Ok(FormatRes {
             rendered: format!("{}{}", shebang, out.rendered),
             lost_comments: out.lost_comments,
             warnings: out.warnings,
+            position_map: position_map,
+            unparsed_items: out.unparsed_items,
         });
     } else {
+        if config.position_map {
+            out.position_map = map_positions(input_tokens, &out.rendered, 0);
+        }
         return Ok(out);
     }
 }
 
+/// Names an intra-doc link could refer to in a file: items defined anywhere in it
+/// (including fields, variants and associated items), and names brought in with
+/// `use`.
+#[derive(Default)]
+struct FileNames {
+    defined: BTreeSet<String>,
+    imported: BTreeSet<String>,
+}
+
+impl FileNames {
+    fn add_fields(&mut self, fields: &syn::Fields) {
+        for f in fields {
+            if let Some(i) = &f.ident {
+                self.defined.insert(i.to_string());
+            }
+        }
+    }
+
+    fn add_use(&mut self, tree: &syn::UseTree) {
+        match tree {
+            syn::UseTree::Path(x) => self.add_use(&x.tree),
+            syn::UseTree::Name(x) => {
+                self.imported.insert(x.ident.to_string());
+            },
+            syn::UseTree::Rename(x) => {
+                self.imported.insert(x.rename.to_string());
+            },
+            syn::UseTree::Glob(_) => { },
+            syn::UseTree::Group(x) => {
+                for t in &x.items {
+                    self.add_use(t);
+                }
+            },
+        }
+    }
+
+    fn add_items(&mut self, items: &[Item]) {
+        for item in items {
+            let ident = match item {
+                Item::Const(x) => Some(&x.ident),
+                Item::Enum(x) => {
+                    for v in &x.variants {
+                        self.defined.insert(v.ident.to_string());
+                        self.add_fields(&v.fields);
+                    }
+                    Some(&x.ident)
+                },
+                Item::ExternCrate(x) => {
+                    self.imported.insert(match &x.rename {
+                        Some((_, r)) => r.to_string(),
+                        None => x.ident.to_string(),
+                    });
+                    None
+                },
+                Item::Fn(x) => Some(&x.sig.ident),
+                Item::ForeignMod(x) => {
+                    for i in &x.items {
+                        match i {
+                            syn::ForeignItem::Fn(x) => self.defined.insert(x.sig.ident.to_string()),
+                            syn::ForeignItem::Static(x) => self.defined.insert(x.ident.to_string()),
+                            syn::ForeignItem::Type(x) => self.defined.insert(x.ident.to_string()),
+                            _ => false,
+                        };
+                    }
+                    None
+                },
+                Item::Impl(x) => {
+                    for i in &x.items {
+                        match i {
+                            ImplItem::Const(x) => self.defined.insert(x.ident.to_string()),
+                            ImplItem::Fn(x) => self.defined.insert(x.sig.ident.to_string()),
+                            ImplItem::Type(x) => self.defined.insert(x.ident.to_string()),
+                            _ => false,
+                        };
+                    }
+                    None
+                },
+                Item::Macro(x) => x.ident.as_ref(),
+                Item::Mod(x) => {
+                    if let Some((_, items)) = &x.content {
+                        self.add_items(items);
+                    }
+                    Some(&x.ident)
+                },
+                Item::Static(x) => Some(&x.ident),
+                Item::Struct(x) => {
+                    self.add_fields(&x.fields);
+                    Some(&x.ident)
+                },
+                Item::Trait(x) => {
+                    for i in &x.items {
+                        match i {
+                            syn::TraitItem::Const(x) => self.defined.insert(x.ident.to_string()),
+                            syn::TraitItem::Fn(x) => self.defined.insert(x.sig.ident.to_string()),
+                            syn::TraitItem::Type(x) => self.defined.insert(x.ident.to_string()),
+                            _ => false,
+                        };
+                    }
+                    Some(&x.ident)
+                },
+                Item::TraitAlias(x) => Some(&x.ident),
+                Item::Type(x) => Some(&x.ident),
+                Item::Union(x) => {
+                    for f in &x.fields.named {
+                        if let Some(i) = &f.ident {
+                            self.defined.insert(i.to_string());
+                        }
+                    }
+                    Some(&x.ident)
+                },
+                Item::Use(x) => {
+                    self.add_use(&x.tree);
+                    None
+                },
+                _ => None,
+            };
+            if let Some(ident) = ident {
+                self.defined.insert(ident.to_string());
+            }
+        }
+    }
+
+    /// Whether a link target path could refer to something in the file. Paths
+    /// starting with a name from `use`, an external crate, a primitive, or something
+    /// in the std prelude can't be checked and are assumed fine.
+    fn resolves(&self, path: &str) -> bool {
+        let mut segments = path.split("::").filter(|s| !matches!(*s, "crate" | "self" | "super" | "Self")).peekable();
+        match segments.peek() {
+            None => return true,
+            Some(first) => if self.imported.contains(*first) ||
+                matches!(
+                    *first,
+                    "std" | "core" | "alloc" | "bool" | "char" | "str" | "u8" | "u16" | "u32" | "u64" | "u128" |
+                        "usize" | "i8" | "i16" | "i32" | "i64" | "i128" | "isize" | "f32" | "f64" | "slice" |
+                        "array" | "tuple" | "unit" | "never" | "reference" | "pointer" | "fn" | "Option" |
+                        "Some" | "None" | "Result" | "Ok" | "Err" | "Vec" | "String" | "Box" | "ToString" |
+                        "ToOwned" | "Clone" | "Copy" | "Send" | "Sync" | "Sized" | "Unpin" | "Drop" | "Fn" |
+                        "FnMut" | "FnOnce" | "Iterator" | "IntoIterator" | "DoubleEndedIterator" |
+                        "ExactSizeIterator" | "Extend" | "FromIterator" | "Default" | "Eq" | "PartialEq" | "Ord" |
+                        "PartialOrd" | "AsRef" | "AsMut" | "From" | "Into" | "TryFrom" | "TryInto" | "Debug" |
+                        "Hash" | "vec" | "format" | "panic" | "assert" | "assert_eq" | "assert_ne" | "println" |
+                        "eprintln" | "write" | "writeln" | "todo" | "unimplemented" | "unreachable"
+                ) {
+                return true;
+            },
+        }
+        segments.all(|s| self.defined.contains(s))
+    }
+}
+
+/// Warnings for intra-doc links in doc comments that don't point to anything in the
+/// file.
+fn check_intra_doc_links(
+    ast: &File,
+    whitespaces: &BTreeMap<HashLineColumn, Vec<Whitespace>>,
+    line_off: usize,
+) -> Vec<loga::Error> {
+    let mut names = FileNames::default();
+    names.add_items(&ast.items);
+    let mut warnings = vec![];
+    for whitespace in whitespaces.values().flatten() {
+        let WhitespaceMode::Comment(comment) = &whitespace.mode else {
+            continue;
+        };
+        if !matches!(comment.mode, CommentMode::DocOuter | CommentMode::DocInner) {
+            continue;
+        }
+        let Ok(targets) = intra_doc_link_targets(&comment.lines) else {
+            continue;
+        };
+        for target in targets {
+            if !names.resolves(&target) {
+                warnings.push(
+                    loga::err_with(
+                        "Intra-doc link target isn't an item in this file",
+                        ea!(line = whitespace.loc.line + line_off, column = whitespace.loc.column, target = target),
+                    ),
+                );
+            }
+        }
+    }
+    warnings
+}
+
+/// The syntax to parse a fragment of source as, for `format_fragment_str`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FragmentKind {
+    Expr,
+    Type,
+    Pat,
+    /// Statements, like the inside of a block.
+    Stmts,
+    /// Items in an `impl` block.
+    ImplItems,
+}
+
+/// Format part of a source file, like a selection in an editor. Every line of the
+/// output is indented `indent` levels.
+pub fn format_fragment_str(
+    source: &str,
+    kind: FragmentKind,
+    indent: usize,
+    config: &FormatConfig,
+) -> Result<FormatRes, loga::Error> {
+    fn child<T: Formattable>(out: &mut MakeSegsState, base_indent: &Alignment, sg: &mut SplitGroupBuilder, ast: &T) {
+        sg.child(ast.make_segs(out, base_indent));
+    }
+
+    fn list<T: FormattableStmt>(
+        out: &mut MakeSegsState,
+        base_indent: &Alignment,
+        sg: &mut SplitGroupBuilder,
+        ast: &Vec<T>,
+    ) {
+        append_statement_list_raw(out, base_indent, sg, None, ast);
+    }
+
+    match kind {
+        FragmentKind::Expr => format_fragment(source, indent, config, syn::parse2::<Expr>, child),
+        FragmentKind::Type => format_fragment(source, indent, config, syn::parse2::<Type>, child),
+        FragmentKind::Pat => format_fragment(
+            source,
+            indent,
+            config,
+            |tokens| Pat::parse_multi_with_leading_vert.parse2(tokens),
+            child,
+        ),
+        FragmentKind::Stmts => format_fragment(
+            source,
+            indent,
+            config,
+            |tokens| Block::parse_within.parse2(tokens),
+            list,
+        ),
+        FragmentKind::ImplItems => format_fragment(source, indent, config, |tokens| (|input: ParseStream| {
+            let mut items = vec![];
+            while !input.is_empty() {
+                items.push(input.parse::<ImplItem>()?);
+            }
+            Ok(items)
+        }).parse2(tokens), list),
+    }
+}
+
+/// Parse a fragment with `parse` and format it with `build_segs`, which adds the
+/// fragment to a split group indented `indent` levels.
+fn format_fragment<T>(
+    source: &str,
+    indent: usize,
+    config: &FormatConfig,
+    parse: impl FnOnce(TokenStream) -> syn::Result<T>,
+    build_segs: impl Fn(&mut MakeSegsState, &Alignment, &mut SplitGroupBuilder, &T),
+) -> Result<FormatRes, loga::Error> {
+    let (whitespaces, tokens) = extract_whitespaces_config(config, source)?;
+    let input_tokens = tokens.clone();
+    let ast =
+        parse(
+            tokens,
+        ).map_err(
+            |e| loga::err_with(
+                "Syn error parsing Rust code",
+                ea!(line = e.span().start().line, column = e.span().start().column, err = e),
+            ),
+        )?;
+    let mut res =
+        format_ast_inner(
+            |out: &mut MakeSegsState, base_indent: &Alignment| {
+                let mut base_indent = base_indent.clone();
+                for _ in 0 .. indent {
+                    base_indent = base_indent.indent();
+                    base_indent.activate();
+                }
+                let mut sg = new_sg(out);
+                sg.split_always(out, base_indent.clone(), true);
+                build_segs(out, &base_indent, &mut sg, &ast);
+                sg.build(out)
+            },
+            config,
+            whitespaces,
+            Some(source),
+            BTreeSet::new(),
+            false,
+            0,
+        )?;
+    if config.position_map {
+        res.position_map = map_positions(input_tokens, &res.rendered, 0);
+    }
+    Ok(res)
+}
+
+/// Split top level tokens into items, parsing each separately. Spans that don't
+/// parse become `Item::Verbatim` and are recorded in `unparsed` with the parse
+/// error.
+fn parse_recover(tokens: TokenStream, unparsed: &mut Vec<(LineColumn, syn::Error)>) -> File {
+    fn is_item_start(trees: &[TokenTree]) -> bool {
+        match trees {
+            [] => true,
+            [TokenTree::Punct(p), ..] => p.as_char() == '#',
+            [TokenTree::Ident(_), TokenTree::Punct(p), ..] if p.as_char() == '!' => true,
+            [TokenTree::Ident(i), ..] => matches!(
+                i.to_string().as_str(),
+                "pub" |
+                    "fn" |
+                    "struct" |
+                    "enum" |
+                    "union" |
+                    "impl" |
+                    "trait" |
+                    "use" |
+                    "mod" |
+                    "const" |
+                    "static" |
+                    "type" |
+                    "extern" |
+                    "unsafe" |
+                    "async"
+            ),
+            _ => false,
+        }
+    }
+
+    let trees = tokens.into_iter().collect::<Vec<_>>();
+    let mut file = File {
+        shebang: None,
+        attrs: vec![],
+        items: vec![],
+    };
+    let mut start = 0usize;
+    for i in 0 .. trees.len() {
+        let boundary = match &trees[i] {
+            TokenTree::Punct(p) => p.as_char() == ';',
+            TokenTree::Group(g) => g.delimiter() == Delimiter::Brace,
+            _ => false,
+        };
+        if !boundary && i + 1 < trees.len() {
+            continue;
+        }
+        let chunk = trees[start ..= i].iter().cloned().collect::<TokenStream>();
+        match syn::parse2::<File>(chunk.clone()) {
+            Ok(chunk_file) => {
+                file.attrs.extend(chunk_file.attrs);
+                file.items.extend(chunk_file.items);
+            },
+            Err(e) => {
+                if !is_item_start(&trees[i + 1..]) {
+                    continue;
+                }
+                unparsed.push((trees[start].span().start(), e));
+                file.items.push(Item::Verbatim(chunk));
+            },
+        }
+        start = i + 1;
+    }
+    file
+}
+
 pub fn format_ast(
     ast: impl Formattable,
     config: &FormatConfig,
     whitespaces: BTreeMap<HashLineColumn, Vec<Whitespace>>,
 ) -> Result<FormatRes, loga::Error> {
+    format_ast_inner(ast, config, whitespaces, None, BTreeSet::new(), false, 0)
+}
+
+/// Format a file that wasn't parsed from source, like one built with `quote!` or by
+/// hand in a build script. Spans aren't used (synthetic or colliding spans are
+/// fine) and there are no comments to preserve. If `doc_comments` is true,
+/// `#[doc = "..."]` attributes are written as `///` and `//!` comments.
+pub fn format_syn_file(file: &File, config: &FormatConfig, doc_comments: bool) -> Result<FormatRes, loga::Error> {
+    format_ast_inner(
+        |out: &mut MakeSegsState, base_indent: &Alignment| file.make_segs(out, base_indent),
+        config,
+        BTreeMap::new(),
+        None,
+        BTreeSet::new(),
+        doc_comments,
+        0,
+    )
+}
+
+/// Parse tokens as a file and format it with `format_syn_file`.
+pub fn format_tokens(tokens: TokenStream, config: &FormatConfig, doc_comments: bool) -> Result<FormatRes, loga::Error> {
+    let file =
+        syn::parse2::<File>(
+            tokens,
+        ).map_err(|e| loga::err_with("Syn error parsing tokens as a file", ea!(err = e)))?;
+    format_syn_file(&file, config, doc_comments)
+}
+
+fn format_ast_inner(
+    ast: impl Formattable,
+    config: &FormatConfig,
+    whitespaces: BTreeMap<HashLineColumn, Vec<Whitespace>>,
+    source: Option<&str>,
+    unparsed: BTreeSet<HashLineColumn>,
+    doc_attrs_as_comments: bool,
+    // Added to line numbers in warnings, for lines removed before parsing (shebang)
+    line_offset: usize,
+) -> Result<FormatRes, loga::Error> {
+    // Line-end comments are placed by position rather than exact token so separate
+    // them
+    let mut whitespaces = whitespaces;
+    let mut line_end_comments = BTreeMap::new();
+    for (k, v) in &mut whitespaces {
+        for w in std::mem::take(v) {
+            match w.mode {
+                WhitespaceMode::LineEndComment(c) => {
+                    line_end_comments.insert(*k, c);
+                },
+                mode => v.push(Whitespace {
+                    loc: w.loc,
+                    mode: mode,
+                }),
+            }
+        }
+    }
+    whitespaces.retain(|_, v| !v.is_empty());
+
     // Build text
     let mut out = MakeSegsState {
         nodes: vec![],
         segs: vec![],
         whitespaces,
         config: config.clone(),
+        source: source.map(|s| s.to_string()),
+        line_end_comments: line_end_comments,
+        unparsed: unparsed,
+        doc_attrs_as_comments: doc_attrs_as_comments,
+        inline_comments: vec![],
     };
     let base_indent = Alignment(Rc::new(RefCell::new(Alignment_ {
         parent: None,
         active: false,
     })));
     let root = ast.make_segs(&mut out, &base_indent);
+
+    if !out.line_end_comments.is_empty() {
+        // Line-end comments after the last token checked for whitespace, add them at
+        // the end
+        let mut sg = new_sg(&mut out);
+        for c in std::mem::take(&mut out.line_end_comments).into_values() {
+            sg.add(&mut out, Segment {
+                node: sg.node,
+                line: None,
+                mode: SegmentMode::All,
+                content: SegmentContent::LineEndComment(base_indent.clone(), c.lines),
+            });
+        }
+        sg.build(&mut out);
+    }
     if out.whitespaces.contains_key(&HashLineColumn(LineColumn {
         line: 0,
         column: 1,
@@ -592,15 +1495,35 @@ pub fn format_ast(
         });
         sg.build(&mut out);
     }
+    space_inline_comments(&mut out);
     let mut lines = Lines {
         lines: vec![],
         owned_lines: vec![],
     };
     {
+        let mut segs = out.segs.iter().enumerate().map(|(i, _)| SegmentIdx(i)).collect::<Vec<_>>();
+
+        // Line-end comments are added before the next token, move them back to directly
+        // after the text they follow
+        for i in 0 .. segs.len() {
+            if !matches!(out.segs.get(segs[i].0).unwrap().content, SegmentContent::LineEndComment(_, _)) {
+                continue;
+            }
+            let mut j = i;
+            while j > 0 && match &out.segs.get(segs[j - 1].0).unwrap().content {
+                SegmentContent::Text(t) => t.trim().is_empty(),
+                SegmentContent::Break(_, _) => true,
+                _ => false,
+            } {
+                j -= 1;
+            }
+            let seg_i = segs.remove(i);
+            segs.insert(j, seg_i);
+        }
         let line_i = LineIdx(lines.owned_lines.len());
         lines.owned_lines.push(Line {
             index: 0,
-            segs: out.segs.iter().enumerate().map(|(i, _)| SegmentIdx(i)).collect(),
+            segs: segs,
         });
         lines.lines.push(line_i);
         for line_i in &lines.lines {
@@ -721,6 +1644,61 @@ pub fn format_ast(
 
     recurse(&mut out, &mut lines, config, root);
 
+    // Nothing can follow a line-end comment on the same line, so split after each
+    // one if needed
+    {
+        let synth_seg_node = new_sg(&mut out).build(&mut out);
+        let mut i = 0usize;
+        while i < lines.lines.len() {
+            let line_i = *lines.lines.get(i).unwrap();
+            let mut res = None;
+            let mut after_comment = None;
+            for (j, seg_i) in lines.owned_lines.get(line_i.0).unwrap().segs.iter().enumerate() {
+                let seg = out.segs.get(seg_i.0).unwrap();
+                let node = out.nodes.get(seg.node.0).unwrap();
+                let visible = match (&seg.mode, node.split) {
+                    (SegmentMode::All, _) => true,
+                    (SegmentMode::Unsplit, true) => false,
+                    (SegmentMode::Unsplit, false) => true,
+                    (SegmentMode::Split, true) => true,
+                    (SegmentMode::Split, false) => false,
+                };
+                match (&seg.content, after_comment.as_ref()) {
+                    (SegmentContent::LineEndComment(a, _), None) => {
+                        after_comment = Some((j, a.clone()));
+                    },
+                    (SegmentContent::Break(_, _), Some(_)) if !visible => {
+                        res = Some(Err(seg.node));
+                        break;
+                    },
+                    (SegmentContent::Text(t), Some((comment_j, a))) if visible && !t.trim().is_empty() => {
+                        res = Some(Ok((*comment_j + 1, a.clone())));
+                        break;
+                    },
+                    _ => { },
+                }
+            }
+            match res {
+                Some(Err(node)) => {
+                    split_group(&mut out, &mut lines, node);
+                },
+                Some(Ok((at, a))) => {
+                    let seg_i = SegmentIdx(out.segs.len());
+                    out.segs.push(Segment {
+                        node: synth_seg_node,
+                        line: None,
+                        mode: SegmentMode::All,
+                        content: SegmentContent::Break(a, true),
+                    });
+                    split_line_at(&mut out, &mut lines, line_i, at, Some(seg_i));
+                },
+                None => {
+                    i += 1;
+                },
+            }
+        }
+    }
+
     // Render
     let mut rendered = String::new();
 
@@ -731,9 +1709,14 @@ pub fn format_ast(
     }
 
     let mut warnings = vec![];
+    let markdown_options = MarkdownOptions {
+        style: config.markdown.clone(),
+        wrap: config.comment_wrap,
+    };
     let lines = lines;
     let mut line_i = 0usize;
     while line_i < lines.lines.len() {
+        let line_start = rendered.len();
         'continue_lineloop : loop {
             let segs =
                 lines.owned_lines.get(lines.lines.get(line_i).unwrap().0).unwrap().segs.iter().filter_map(|seg_i| {
@@ -807,7 +1790,9 @@ pub fn format_ast(
                                         continue;
                                     }
                                 },
-                                WhitespaceMode::Comment(comment) => {
+                                WhitespaceMode::Comment(comment) |
+                                WhitespaceMode::LineEndComment(comment) |
+                                WhitespaceMode::InlineBlockComment(comment) => {
                                     if comment_i > 0 {
                                         push!("\n");
                                     }
@@ -830,20 +1815,33 @@ pub fn format_ast(
                                         CommentMode::Normal if config.explicit_markdown_comments => {
                                             true
                                         },
+                                        _ if config
+                                            .detect_preformatted
+                                            .as_ref()
+                                            .is_some_and(|d| looks_preformatted(&comment.lines, d)) => {
+                                            warnings.push(
+                                                loga::err_with(
+                                                    "Comment looks preformatted (ex: a diagram or table) so it was left as-is; mark it verbatim with `//.` (or use a code block in doc comments) to silence this",
+                                                    ea!(line = whitespace.loc.line + line_offset, column = whitespace.loc.column),
+                                                ),
+                                            );
+                                            true
+                                        },
                                         _ => {
-                                            match format_md(
+                                            match format_md_styled(
                                                 &mut rendered,
                                                 config.max_width,
                                                 config.comment_width,
                                                 &prefix,
                                                 &comment.lines,
+                                                &markdown_options,
                                             ) {
                                                 Err(e) => {
                                                     let err =
                                                         loga::err_with(
                                                             "Error formatting comments",
                                                             ea!(
-                                                                line = whitespace.loc.line,
+                                                                line = whitespace.loc.line + line_offset,
                                                                 column = whitespace.loc.column,
                                                                 comments = comment.lines
                                                             ),
@@ -867,13 +1865,29 @@ pub fn format_ast(
                                                 push!("\n");
                                             }
                                             let line = line.strip_prefix(' ').unwrap_or(line);
-                                            push!(&format!("{}{}", prefix, line.trim_end()));
+                                            push!(format!("{}{}", prefix, line).trim_end());
                                         }
                                     }
                                 },
                             }
                         }
                     },
+                    SegmentContent::LineEndComment(b, text) => {
+                        let comment = format!(" // {}", text);
+                        let width = rendered[line_start..].chars().map(|c| match c {
+                            '\t' => config.indent_spaces,
+                            _ => 1,
+                        }).sum::<usize>();
+                        if width + comment.chars().count() <= config.max_width {
+                            push!(&comment);
+                        } else {
+                            // Doesn't fit, move before the line
+                            rendered.insert_str(
+                                line_start,
+                                &format!("{}// {}\n", render_indent(config, b.get()), text),
+                            );
+                        }
+                    },
                 }
             }
             push!("\n");
@@ -885,5 +1899,7 @@ pub fn format_ast(
         rendered: rendered,
         lost_comments: out.whitespaces,
         warnings: warnings,
+        position_map: vec![],
+        unparsed_items: 0,
     })
 }
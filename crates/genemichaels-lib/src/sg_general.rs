@@ -9,13 +9,20 @@ use {
         sg_type::build_path,
         whitespace::HashLineColumn,
         Alignment,
+        Comment,
+        CommentMode,
         Formattable,
         FormattablePunct,
         FormattableStmt,
         MakeSegsState,
+        MacroStrategy,
         MarginGroup,
         SplitGroupBuilder,
         SplitGroupIdx,
+        SegmentIdx,
+        Segment,
+        SegmentContent,
+        SegmentMode,
         Whitespace,
         WhitespaceMode,
     },
@@ -31,7 +38,10 @@ use {
     },
     std::{
         fmt::Write,
-        ops::Bound,
+        ops::{
+            Bound,
+            Range,
+        },
     },
     syn::{
         spanned::Spanned,
@@ -44,12 +54,18 @@ use {
             Plus,
         },
         Attribute,
+        parse::Parser,
+        punctuated::Punctuated,
         Block,
         Expr,
         ExprCall,
+        ExprLit,
+        File,
         Item,
+        Lit,
         Macro,
         MacroDelimiter,
+        Path,
         Stmt,
     },
 };
@@ -140,6 +156,75 @@ pub(crate) fn append_attr(
     });
 }
 
+/// The text of a `#[doc = "..."]` attribute, if it is one and doc attributes are
+/// being written as comments.
+fn doc_attr_text(out: &MakeSegsState, attr: &Attribute) -> Option<String> {
+    if !out.doc_attrs_as_comments {
+        return None;
+    }
+    let syn::Meta::NameValue(m) = &attr.meta else {
+        return None;
+    };
+    if !m.path.is_ident("doc") {
+        return None;
+    }
+    let Expr::Lit(ExprLit { lit: Lit::Str(s), .. }) = &m.value else {
+        return None;
+    };
+    Some(s.value())
+}
+
+/// Write a doc attribute as a `///` or `//!` comment, merging with the comment for
+/// the directly preceding doc attribute if there is one.
+fn append_doc_attr_comment(
+    out: &mut MakeSegsState,
+    base_indent: &Alignment,
+    sg: &mut SplitGroupBuilder,
+    attr: &Attribute,
+    text: String,
+) {
+    let mode = match attr.style {
+        syn::AttrStyle::Outer => CommentMode::DocOuter,
+        syn::AttrStyle::Inner(_) => CommentMode::DocInner,
+    };
+    for seg_i in sg.segs.iter().rev() {
+        match &mut out.segs.get_mut(seg_i.0).unwrap().content {
+            SegmentContent::Break(_, _) => continue,
+            SegmentContent::Text(t) if t.trim().is_empty() => continue,
+            SegmentContent::Whitespace((_, whitespaces)) => {
+                if let Some(Whitespace { mode: WhitespaceMode::Comment(previous), .. }) = whitespaces.last_mut() {
+                    if previous.mode == mode {
+                        previous.lines.push('\n');
+                        previous.lines.push_str(&text);
+                        return;
+                    }
+                }
+            },
+            _ => { },
+        }
+        break;
+    }
+    sg.add(out, Segment {
+        node: sg.node,
+        line: None,
+        mode: SegmentMode::All,
+        content: SegmentContent::Whitespace((base_indent.clone(), vec![Whitespace {
+            loc: attr.pound_token.span.start(),
+            mode: WhitespaceMode::Comment(Comment {
+                mode: mode,
+                lines: text,
+            }),
+        }])),
+    });
+}
+
+/// Attributes that disable formatting for the element they're attached to:
+/// `rustfmt::skip` or `genemichaels::skip` (also within `cfg_attr`).
+pub(crate) fn is_skip_attr(attr: &Attribute) -> bool {
+    let meta = attr.meta.to_token_stream().to_string();
+    meta.contains("rustfmt :: skip") || meta.contains("genemichaels :: skip")
+}
+
 pub(crate) fn append_statement_list_raw(
     out: &mut MakeSegsState,
     base_indent: &Alignment,
@@ -161,6 +246,10 @@ pub(crate) fn append_statement_list_raw(
             },
             syn::AttrStyle::Inner(_) => { },
         };
+        if let Some(text) = doc_attr_text(out, attr) {
+            append_doc_attr_comment(out, base_indent, sg, attr, text);
+            continue;
+        }
         if i > 0 {
             sg.split_if(out, base_indent.clone(), out.config.split_attributes, false);
         }
@@ -171,7 +260,20 @@ pub(crate) fn append_statement_list_raw(
         previous_margin_group = MarginGroup::Attr;
         i += 1;
     }
-    for el in block {
+    let mut el_i = 0;
+    while el_i < block.len() {
+        let el = &block[el_i];
+        let region = if out.source.is_some() && skip_marker(out, el) == Some(SkipMarker::Off) {
+            let region_end =
+                (el_i + 1 .. block.len())
+                    .find(|j| skip_marker(out, &block[*j]) == Some(SkipMarker::On))
+                    .unwrap_or(block.len());
+
+            // Do this first so only comments before the marker affect the margin
+            Some((region_end, skip_region_range(out, block, el_i, region_end)))
+        } else {
+            None
+        };
         let (new_margin_group, want_margin) = el.want_margin();
         if i > 0 {
             if previous_margin_group != new_margin_group || want_margin || has_comments(out, el) {
@@ -179,13 +281,215 @@ pub(crate) fn append_statement_list_raw(
             }
             sg.split(out, base_indent.clone(), true);
         }
-        sg.child((el).make_segs(out, base_indent));
+        if let Some((region_end, range)) = region {
+            sg.child(new_sg_skip_region_range(out, base_indent, &block[el_i .. region_end], range));
+            previous_margin_group = block[region_end - 1].want_margin().0;
+            el_i = region_end;
+        } else {
+            sg.child((el).make_segs(out, base_indent));
+            previous_margin_group = new_margin_group;
+            el_i += 1;
+        }
         sg.seg_unsplit(out, " ");
-        previous_margin_group = new_margin_group;
         i += 1;
     }
 }
 
+#[derive(PartialEq, Clone, Copy)]
+enum SkipMarker {
+    Off,
+    On,
+}
+
+fn skip_marker_line(line: &str) -> Option<SkipMarker> {
+    match line.trim() {
+        "genemichaels: off" => Some(SkipMarker::Off),
+        "genemichaels: on" => Some(SkipMarker::On),
+        _ => None,
+    }
+}
+
+/// Find the last `genemichaels: off` or `genemichaels: on` marker in the comments
+/// before an element.
+fn skip_marker(out: &MakeSegsState, el: impl ToTokens) -> Option<SkipMarker> {
+    let first = el.to_token_stream().into_iter().next()?;
+    let whitespaces = out.whitespaces.get(&HashLineColumn(first.span().start()))?;
+    let mut found = None;
+    for w in whitespaces {
+        let WhitespaceMode::Comment(c) = &w.mode else {
+            continue;
+        };
+        if !matches!(c.mode, CommentMode::Normal | CommentMode::ExplicitNormal) {
+            continue;
+        }
+        for line in c.lines.lines() {
+            if let Some(m) = skip_marker_line(line) {
+                found = Some(m);
+            }
+        }
+    }
+    found
+}
+
+/// The byte range of the last `//` comment line in the source text that's the
+/// marker, without indentation or newline.
+fn find_skip_marker_line(text: &str, marker: SkipMarker) -> Option<Range<usize>> {
+    let mut found = None;
+    let mut at = 0;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim();
+        let comment = trimmed.strip_prefix("//?").or_else(|| trimmed.strip_prefix("//"));
+        if comment.and_then(skip_marker_line) == Some(marker) {
+            let start = at + line.len() - line.trim_start().len();
+            found = Some(start .. start + trimmed.len());
+        }
+        at += line.len();
+    }
+    found
+}
+
+/// Remove the comments up to and including the last marker line from the
+/// whitespace before an element, since they're passed through with the skipped
+/// region. If `keep_before` the comments before the marker are kept instead of the
+/// ones after.
+fn split_skip_marker_comments(out: &mut MakeSegsState, loc: LineColumn, marker: SkipMarker, keep_before: bool) {
+    let Some(whitespaces) = out.whitespaces.remove(&HashLineColumn(loc)) else {
+        return;
+    };
+    let mut split_at = None;
+    for (i, w) in whitespaces.iter().enumerate() {
+        let WhitespaceMode::Comment(c) = &w.mode else {
+            continue;
+        };
+        if !matches!(c.mode, CommentMode::Normal | CommentMode::ExplicitNormal) {
+            continue;
+        }
+        for (j, line) in c.lines.lines().enumerate() {
+            if skip_marker_line(line) == Some(marker) {
+                split_at = Some((i, j));
+            }
+        }
+    }
+    let Some((split_i, split_j)) = split_at else {
+        out.whitespaces.insert(HashLineColumn(loc), whitespaces);
+        return;
+    };
+    let mut kept = vec![];
+    for (i, w) in whitespaces.into_iter().enumerate() {
+        if i == split_i {
+            let WhitespaceMode::Comment(c) = w.mode else {
+                unreachable!();
+            };
+            let lines = c.lines.lines().collect::<Vec<_>>();
+            let lines = if keep_before {
+                &lines[..split_j]
+            } else {
+                &lines[split_j + 1..]
+            };
+            if !lines.is_empty() {
+                kept.push(Whitespace {
+                    loc: w.loc,
+                    mode: WhitespaceMode::Comment(Comment {
+                        mode: c.mode,
+                        lines: lines.join("\n"),
+                    }),
+                });
+            }
+        } else if (i < split_i) == keep_before {
+            kept.push(w);
+        }
+    }
+    if !kept.is_empty() {
+        out.whitespaces.insert(HashLineColumn(loc), kept);
+    }
+}
+
+/// Find the source range of a skipped region starting at `block[start_i]` and
+/// ending before `block[end_i]`: from the `genemichaels: off` marker line through
+/// the `genemichaels: on` marker line, or a line-end comment after the last element
+/// if there's no `on` marker. The comments covered by the range are removed from
+/// the first and next elements' whitespace.
+fn skip_region_range(
+    out: &mut MakeSegsState,
+    block: &[impl FormattableStmt],
+    start_i: usize,
+    end_i: usize,
+) -> Range<usize> {
+    let source = out.source.clone().unwrap();
+    let first = &block[start_i];
+    let last = &block[end_i - 1];
+    let mut start = first.span().byte_range().start;
+    let search_from = match start_i {
+        0 => 0,
+        i => block[i - 1].span().byte_range().end,
+    };
+    if let Some(marker) = find_skip_marker_line(&source[search_from .. start], SkipMarker::Off) {
+        start = search_from + marker.start;
+        let first_start = first.to_token_stream().into_iter().next().unwrap().span().start();
+        split_skip_marker_comments(out, first_start, SkipMarker::Off, true);
+    }
+    let end = last.span().byte_range().end;
+    if let Some(next) = block.get(end_i) {
+        let next_start = next.to_token_stream().into_iter().next().unwrap().span();
+        if let Some(marker) =
+            find_skip_marker_line(&source[end .. next_start.byte_range().start], SkipMarker::On) {
+            split_skip_marker_comments(out, next_start.start(), SkipMarker::On, false);
+            return start .. end + marker.end;
+        }
+    }
+    start .. with_line_end_comment(&source, end)
+}
+
+/// Extend the end of a verbatim range to keep a line-end comment after it.
+fn with_line_end_comment(source: &str, end: usize) -> usize {
+    let rest_of_line = source[end..].split('\n').next().unwrap().trim_end();
+    if rest_of_line.trim_start().starts_with("//") {
+        end + rest_of_line.len()
+    } else {
+        end
+    }
+}
+
+/// Pass through a run of elements unchanged, with the comments before them
+/// formatted normally.
+pub(crate) fn new_sg_skip_region(
+    out: &mut MakeSegsState,
+    base_indent: &Alignment,
+    els: &[impl FormattableStmt],
+) -> SplitGroupIdx {
+    let source = out.source.clone().unwrap();
+    let start = els.first().unwrap().span().byte_range().start;
+    let end = with_line_end_comment(&source, els.last().unwrap().span().byte_range().end);
+    new_sg_skip_region_range(out, base_indent, els, start .. end)
+}
+
+/// Like `new_sg_skip_region` but passes through exactly `range` of the source, for
+/// regions that also cover their `genemichaels: off` and `genemichaels: on` marker
+/// comments.
+fn new_sg_skip_region_range(
+    out: &mut MakeSegsState,
+    base_indent: &Alignment,
+    els: &[impl FormattableStmt],
+    range: Range<usize>,
+) -> SplitGroupIdx {
+    let first = els.first().unwrap();
+    let last = els.last().unwrap();
+    let source = out.source.clone().unwrap();
+    let mut sg = new_sg(out);
+    let first_start = first.to_token_stream().into_iter().next().unwrap().span().start();
+    append_whitespace(out, base_indent, &mut sg, first_start);
+    let text = &source[range];
+    sg.seg(out, text);
+    consume_verbatim_whitespaces(
+        out,
+        base_indent,
+        &mut sg,
+        text,
+        (Bound::Excluded(HashLineColumn(first_start)), Bound::Included(HashLineColumn(last.span().end()))),
+    );
+    sg.build(out)
+}
+
 pub(crate) fn append_bracketed_statement_list(
     out: &mut MakeSegsState,
     base_indent: &Alignment,
@@ -223,6 +527,64 @@ pub(crate) fn new_sg_block(
     sg.build(out)
 }
 
+/// Remove whitespace within a range of source that's being passed through
+/// verbatim, since the comments are already in the source text.
+fn consume_verbatim_whitespaces(
+    out: &mut MakeSegsState,
+    base_indent: &Alignment,
+    sg: &mut SplitGroupBuilder,
+    text: &str,
+    range: (Bound<HashLineColumn>, Bound<HashLineColumn>),
+) {
+    let remove_line_end_keys = out.line_end_comments.range(range).map(|x| *x.0).collect::<Vec<_>>();
+    for k in remove_line_end_keys {
+        let comment = out.line_end_comments.remove(&k).unwrap();
+        if text.contains(&comment.lines) {
+            continue;
+        }
+        sg.add(out, crate::Segment {
+            node: sg.node,
+            line: None,
+            mode: crate::SegmentMode::All,
+            content: crate::SegmentContent::LineEndComment(base_indent.clone(), comment.lines),
+        });
+    }
+    let remove_whitespaces_keys = out.whitespaces.range(range).map(|x| *x.0).collect::<Vec<_>>();
+    for k in remove_whitespaces_keys {
+        let ws = out.whitespaces.remove(&k).unwrap();
+
+        // Line end comments after the end of the unformatted span will get lost since
+        // they should be included by span formatting. This checks if they've been missed
+        // and smashes them on the end.
+        for ws in ws {
+            match ws.mode {
+                WhitespaceMode::BlankLines(_) => { },
+                WhitespaceMode::Comment(comment) |
+                WhitespaceMode::LineEndComment(comment) |
+                WhitespaceMode::InlineBlockComment(comment) => {
+                    // Each line has its own `//` and indentation in the source so check them
+                    // separately
+                    if comment.lines.lines().all(|l| text.contains(l)) {
+                        continue;
+                    }
+                    sg.add(out, crate::Segment {
+                        node: sg.node,
+                        line: None,
+                        mode: crate::SegmentMode::All,
+                        content: crate::SegmentContent::Whitespace((base_indent.clone(), vec![Whitespace {
+                            loc: k.0,
+                            mode: WhitespaceMode::Comment(crate::Comment {
+                                mode: crate::CommentMode::Normal,
+                                lines: comment.lines,
+                            }),
+                        }])),
+                    });
+                },
+            }
+        }
+    }
+}
+
 pub(crate) fn new_sg_outer_attrs(
     out: &mut MakeSegsState,
     base_indent: &Alignment,
@@ -242,12 +604,16 @@ pub(crate) fn new_sg_outer_attrs(
                 continue;
             },
         };
+        if let Some(text) = doc_attr_text(out, attr) {
+            append_doc_attr_comment(out, base_indent, &mut sg, attr, text);
+            continue;
+        }
         append_attr(out, base_indent, &mut sg, attr);
         if !out.config.split_attributes {
             sg.seg_unsplit(out, " ");
         }
         sg.split_if(out, base_indent.clone(), out.config.split_attributes, false);
-        if attr.meta.to_token_stream().to_string().contains("rustfmt :: skip") {
+        if is_skip_attr(attr) {
             skip_fmt = true;
         }
     }
@@ -271,41 +637,13 @@ pub(crate) fn new_sg_outer_attrs(
                 after_attr_line_col = Bound::Included(HashLineColumn(span_including_attrs.start()));
             }
             sg.seg(out, &text[after_attr_byte_offset - span_including_attrs.span().byte_range().start..]);
-            let remove_whitespaces_keys =
-                out
-                    .whitespaces
-                    .range((after_attr_line_col, Bound::Included(HashLineColumn(span_including_attrs.end()))))
-                    .map(|x| *x.0)
-                    .collect::<Vec<_>>();
-            for k in remove_whitespaces_keys {
-                let ws = out.whitespaces.remove(&k).unwrap();
-
-                // Line end comments after the end of the unformatted span will get lost since
-                // they should be included by span formatting. This checks if they've been missed
-                // and smashes them on the end.
-                for ws in ws {
-                    match ws.mode {
-                        WhitespaceMode::BlankLines(_) => { },
-                        WhitespaceMode::Comment(comment) => {
-                            if text.contains(&comment.lines) {
-                                continue;
-                            }
-                            sg.add(out, crate::Segment {
-                                node: sg.node,
-                                line: None,
-                                mode: crate::SegmentMode::All,
-                                content: crate::SegmentContent::Whitespace((base_indent.clone(), vec![Whitespace {
-                                    loc: k.0,
-                                    mode: WhitespaceMode::Comment(crate::Comment {
-                                        mode: crate::CommentMode::Normal,
-                                        lines: comment.lines,
-                                    }),
-                                }])),
-                            });
-                        },
-                    }
-                }
-            }
+            consume_verbatim_whitespaces(
+                out,
+                base_indent,
+                &mut sg,
+                &text,
+                (after_attr_line_col, Bound::Included(HashLineColumn(span_including_attrs.end()))),
+            );
             break 'child;
         }
         sg.child(child.make_segs(out, base_indent));
@@ -313,6 +651,19 @@ pub(crate) fn new_sg_outer_attrs(
     sg.build(out)
 }
 
+/// Look up a configured strategy for a macro, first by full path then by name.
+fn macro_strategy(out: &MakeSegsState, path: &Path) -> Option<MacroStrategy> {
+    if out.config.macros.is_empty() {
+        return None;
+    }
+    let full = path.segments.iter().map(|s| s.ident.to_string()).collect::<Vec<_>>().join("::");
+    if let Some(s) = out.config.macros.get(&full) {
+        return Some(*s);
+    }
+    let name = path.segments.last()?.ident.to_string();
+    return out.config.macros.get(&name).copied();
+}
+
 pub(crate) fn append_macro_bracketed(
     out: &mut MakeSegsState,
     base_indent: &Alignment,
@@ -320,7 +671,31 @@ pub(crate) fn append_macro_bracketed(
     mac: &Macro,
     semi: bool,
 ) {
-    append_macro_body_bracketed(out, base_indent, sg, &mac.delimiter, mac.tokens.clone());
+    let strategy = macro_strategy(out, &mac.path);
+    'body: {
+        'to_formatted: {
+            if strategy != Some(MacroStrategy::Verbatim) {
+                break 'to_formatted;
+            }
+            let delim_span = mac.delimiter.span();
+            let Some(text) = delim_span.join().source_text() else {
+                break 'to_formatted;
+            };
+            sg.seg(out, &text);
+            consume_verbatim_whitespaces(
+                out,
+                base_indent,
+                sg,
+                &text,
+                (
+                    Bound::Excluded(HashLineColumn(delim_span.open().start())),
+                    Bound::Included(HashLineColumn(delim_span.close().start())),
+                ),
+            );
+            break 'body;
+        }
+        append_macro_body_bracketed_as(out, base_indent, sg, &mac.delimiter, mac.tokens.clone(), strategy);
+    }
     if semi {
         sg.seg(out, ";");
     }
@@ -345,6 +720,17 @@ pub(crate) fn append_macro_body_bracketed(
     sg: &mut SplitGroupBuilder,
     delim: &MacroDelimiter,
     tokens: TokenStream,
+) {
+    append_macro_body_bracketed_as(out, base_indent, sg, delim, tokens, None);
+}
+
+fn append_macro_body_bracketed_as(
+    out: &mut MakeSegsState,
+    base_indent: &Alignment,
+    sg: &mut SplitGroupBuilder,
+    delim: &MacroDelimiter,
+    tokens: TokenStream,
+    strategy: Option<MacroStrategy>,
 ) {
     let indent = base_indent.indent();
     match delim {
@@ -352,7 +738,7 @@ pub(crate) fn append_macro_body_bracketed(
             sg.seg(out, "(");
             if !tokens.is_empty() || out.whitespaces.contains_key(&HashLineColumn(x.span.close().start())) {
                 sg.split(out, indent.clone(), true);
-                append_macro_body(out, &indent, sg, tokens);
+                append_macro_body_as(out, &indent, sg, tokens, strategy);
                 append_whitespace(out, base_indent, sg, x.span.close().start());
             }
             sg.split(out, base_indent.clone(), false);
@@ -363,7 +749,7 @@ pub(crate) fn append_macro_body_bracketed(
             sg.initial_split();
             if !tokens.is_empty() || out.whitespaces.contains_key(&HashLineColumn(x.span.close().start())) {
                 sg.split(out, indent.clone(), true);
-                append_macro_body(out, &indent, sg, tokens);
+                append_macro_body_as(out, &indent, sg, tokens, strategy);
                 append_whitespace(out, base_indent, sg, x.span.close().start());
             }
             sg.split(out, base_indent.clone(), false);
@@ -373,7 +759,7 @@ pub(crate) fn append_macro_body_bracketed(
             sg.seg(out, "[");
             if !tokens.is_empty() || out.whitespaces.contains_key(&HashLineColumn(x.span.close().start())) {
                 sg.split(out, indent.clone(), true);
-                append_macro_body(out, &indent, sg, tokens);
+                append_macro_body_as(out, &indent, sg, tokens, strategy);
                 append_whitespace(out, base_indent, sg, x.span.close().start());
             }
             sg.split(out, base_indent.clone(), false);
@@ -388,6 +774,44 @@ pub(crate) fn append_macro_body(
     sg: &mut SplitGroupBuilder,
     tokens: TokenStream,
 ) {
+    append_macro_body_as(out, base_indent, sg, tokens, None);
+}
+
+/// Format a macro body, using the configured strategy if there is one. If the
+/// body doesn't parse per the strategy this falls back to the heuristics.
+fn append_macro_body_as(
+    out: &mut MakeSegsState,
+    base_indent: &Alignment,
+    sg: &mut SplitGroupBuilder,
+    tokens: TokenStream,
+    strategy: Option<MacroStrategy>,
+) {
+    match strategy {
+        None | Some(MacroStrategy::Verbatim) => { },
+        Some(MacroStrategy::Exprs) => {
+            if let Ok(exprs) = Punctuated::<Expr, Comma>::parse_terminated.parse2(tokens.clone()) {
+                append_inline_list_raw(out, base_indent, sg, ",", &exprs, InlineListSuffix::<Expr>::VerbatimPunct);
+                return;
+            }
+        },
+        Some(MacroStrategy::Block) => {
+            if let Ok(stmts) = Block::parse_within.parse2(tokens.clone()) {
+                append_statement_list_raw(out, base_indent, sg, None, &stmts);
+                return;
+            }
+        },
+        Some(MacroStrategy::Items) => {
+            if let Ok(file) = syn::parse2::<File>(tokens.clone()) {
+                append_statement_list_raw(out, base_indent, sg, Some(&file.attrs), &file.items);
+                return;
+            }
+        },
+        Some(MacroStrategy::Freeform) => {
+            append_macro_body_substreams(out, base_indent, sg, tokens, false);
+            return;
+        },
+    }
+
     // Try to parse entire macro like a function call
     if let Ok(exprs) = syn::parse2::<ExprCall>(quote!{
         f(#tokens)
@@ -418,8 +842,19 @@ pub(crate) fn append_macro_body(
         }
     }
 
-    // Split token stream into "expressions" (/substream) using `;` and `,` and then
-    // try to format each expression.
+    append_macro_body_substreams(out, base_indent, sg, tokens, true);
+}
+
+/// Split token stream into "expressions" (/substream) using `;` and `,` and then
+/// try to format each expression. If `parse` is false, each substream is
+/// formatted freeform without trying to parse it.
+fn append_macro_body_substreams(
+    out: &mut MakeSegsState,
+    base_indent: &Alignment,
+    sg: &mut SplitGroupBuilder,
+    tokens: TokenStream,
+    parse: bool,
+) {
     let mut substreams: Vec<(Vec<TokenTree>, Option<Punct>)> = vec![];
     {
         let mut top = vec![];
@@ -464,40 +899,42 @@ pub(crate) fn append_macro_body(
             let tokens = TokenStream::from_iter(sub.0);
             let punct = sub.1;
 
-            // Try to parse current expression/substream as a function call
-            if let Ok(exprs) = syn::parse2::<ExprCall>(quote!{
-                f(#tokens #punct)
-            }) {
-                assert!(exprs.args.len() <= 1);
-                if exprs.args.len() == 1 && matches!(exprs.args.iter().next(), Some(Expr::Verbatim(_))) {
-                    // not really parsed, continue
-                } else {
-                    if let Some(e) = exprs.args.iter().next() {
-                        sg.child(e.make_segs(out, base_indent));
-                    }
-                    if let Some(suf) = punct {
-                        append_whitespace(out, base_indent, sg, suf.span().start());
-                        sg.seg(out, suf);
+            if parse {
+                // Try to parse current expression/substream as a function call
+                if let Ok(exprs) = syn::parse2::<ExprCall>(quote!{
+                    f(#tokens #punct)
+                }) {
+                    assert!(exprs.args.len() <= 1);
+                    if exprs.args.len() == 1 && matches!(exprs.args.iter().next(), Some(Expr::Verbatim(_))) {
+                        // not really parsed, continue
+                    } else {
+                        if let Some(e) = exprs.args.iter().next() {
+                            sg.child(e.make_segs(out, base_indent));
+                        }
+                        if let Some(suf) = punct {
+                            append_whitespace(out, base_indent, sg, suf.span().start());
+                            sg.seg(out, suf);
+                        }
+                        break 'nextsub;
                     }
-                    break 'nextsub;
                 }
-            }
 
-            // Try to parse current expression/substream as a block
-            if let Ok(block) = syn::parse2::<Block>(quote!{
-                {
-                    #tokens #punct
-                }
-            }) {
-                if block.stmts.len() == 1 &&
-                    matches!(
-                        block.stmts.first(),
-                        Some(Stmt::Item(Item::Verbatim(_))) | Some(Stmt::Expr(Expr::Verbatim(_), _))
-                    ) {
-                    // not really parsed, continue
-                } else {
-                    append_statement_list_raw(out, base_indent, sg, None, &block.stmts);
-                    break 'nextsub;
+                // Try to parse current expression/substream as a block
+                if let Ok(block) = syn::parse2::<Block>(quote!{
+                    {
+                        #tokens #punct
+                    }
+                }) {
+                    if block.stmts.len() == 1 &&
+                        matches!(
+                            block.stmts.first(),
+                            Some(Stmt::Item(Item::Verbatim(_))) | Some(Stmt::Expr(Expr::Verbatim(_), _))
+                        ) {
+                        // not really parsed, continue
+                    } else {
+                        append_statement_list_raw(out, base_indent, sg, None, &block.stmts);
+                        break 'nextsub;
+                    }
                 }
             }
 
@@ -527,6 +964,11 @@ pub(crate) fn append_macro_body(
                     };
                 }
 
+                let nested_strategy = if parse {
+                    None
+                } else {
+                    Some(MacroStrategy::Freeform)
+                };
                 let mut previous: Option<TokenTree> = None;
                 for t in tokens {
                     match &t {
@@ -537,32 +979,32 @@ pub(crate) fn append_macro_body(
                                 let indent = base_indent.indent();
                                 match g.delimiter() {
                                     proc_macro2::Delimiter::Parenthesis => {
-                                        append_macro_body_bracketed(out, &indent, &mut sg, &MacroDelimiter::Paren({
+                                        append_macro_body_bracketed_as(out, &indent, &mut sg, &MacroDelimiter::Paren({
                                             let mut delim = Paren::default();
                                             delim.span = g.delim_span();
                                             delim
-                                        }), g.stream());
+                                        }), g.stream(), nested_strategy);
                                     },
                                     proc_macro2::Delimiter::Brace => {
                                         if is_hetero_push_next(&previous) {
                                             sg.seg(out, " ");
                                         }
-                                        append_macro_body_bracketed(out, &indent, &mut sg, &MacroDelimiter::Brace({
+                                        append_macro_body_bracketed_as(out, &indent, &mut sg, &MacroDelimiter::Brace({
                                             let mut delim = Brace::default();
                                             delim.span = g.delim_span();
                                             delim
-                                        }), g.stream());
+                                        }), g.stream(), nested_strategy);
                                     },
                                     proc_macro2::Delimiter::Bracket => {
-                                        append_macro_body_bracketed(out, &indent, &mut sg, &MacroDelimiter::Bracket({
+                                        append_macro_body_bracketed_as(out, &indent, &mut sg, &MacroDelimiter::Bracket({
                                             let mut delim = Bracket::default();
                                             delim.span = g.delim_span();
                                             delim
-                                        }), g.stream());
+                                        }), g.stream(), nested_strategy);
                                     },
                                     proc_macro2::Delimiter::None => {
                                         // TODO needs verification
-                                        append_macro_body(out, &indent, &mut sg, g.stream());
+                                        append_macro_body_as(out, &indent, &mut sg, g.stream(), nested_strategy);
                                     },
                                 }
                                 sg.build(out)
@@ -623,10 +1065,25 @@ pub(crate) fn append_whitespace(
     sg: &mut SplitGroupBuilder,
     loc: LineColumn,
 ) {
+    // Line-end comments following tokens already added go here, before the next
+    // token
+    if !out.line_end_comments.is_empty() {
+        let keys = out.line_end_comments.range(..HashLineColumn(loc)).map(|x| *x.0).collect::<Vec<_>>();
+        for k in keys {
+            let comment = out.line_end_comments.remove(&k).unwrap();
+            sg.add(out, crate::Segment {
+                node: sg.node,
+                line: None,
+                mode: crate::SegmentMode::All,
+                content: crate::SegmentContent::LineEndComment(base_indent.clone(), comment.lines),
+            });
+        }
+    }
     let whitespace = match out.whitespaces.remove(&HashLineColumn(loc)) {
         Some(c) => c,
         None => return,
     };
+    let mut inline_comments = vec![];
     let whitespace: Vec<Whitespace> = whitespace.into_iter().filter_map(|w| {
         match w.mode {
             WhitespaceMode::BlankLines(l) => {
@@ -639,31 +1096,80 @@ pub(crate) fn append_whitespace(
                     mode: WhitespaceMode::BlankLines(use_lines),
                 });
             },
-            WhitespaceMode::Comment(c) => {
+            WhitespaceMode::Comment(c) | WhitespaceMode::LineEndComment(c) => {
                 return Some(Whitespace {
                     loc: w.loc,
                     mode: WhitespaceMode::Comment(c),
                 });
             },
+            WhitespaceMode::InlineBlockComment(c) => {
+                inline_comments.push(c);
+                return None;
+            },
         }
     }).collect();
-    if whitespace.is_empty() {
+    if !whitespace.is_empty() {
+        sg.add(out, crate::Segment {
+            node: sg.node,
+            line: None,
+            mode: crate::SegmentMode::All,
+            content: crate::SegmentContent::Whitespace((base_indent.clone(), whitespace)),
+        });
+    }
+    if inline_comments.is_empty() {
         return;
     }
-    sg.add(out, crate::Segment {
-        node: sg.node,
-        line: None,
-        mode: crate::SegmentMode::All,
-        content: crate::SegmentContent::Whitespace((base_indent.clone(), whitespace)),
-    });
+
+    // Spacing depends on the text on both sides so it's added later, in
+    // `space_inline_comments`
+    for c in inline_comments {
+        out.inline_comments.push(SegmentIdx(out.segs.len()));
+        sg.seg(out, format!("/* {} */", c.lines));
+    }
+}
+
+/// Space inline block comments from the surrounding text: a space before unless
+/// following an opening delimiter or whitespace, and a space after unless followed
+/// by a closing delimiter, punctuation, or whitespace.
+pub(crate) fn space_inline_comments(out: &mut MakeSegsState) {
+    fn text(out: &MakeSegsState, i: usize) -> Option<&str> {
+        match &out.segs[i].content {
+            SegmentContent::Text(t) if !t.is_empty() => Some(t),
+            _ => None,
+        }
+    }
+
+    for seg_i in std::mem::take(&mut out.inline_comments) {
+        let prev = (0 .. seg_i.0).rev().find_map(|i| text(out, i)).and_then(|t| t.chars().last());
+        let next = (seg_i.0 + 1 .. out.segs.len()).find_map(|i| text(out, i)).and_then(|t| t.chars().next());
+        let space_before = prev.is_some_and(|c| !c.is_whitespace() && !matches!(c, '(' | '[' | '{' | '<'));
+        let space_after =
+            next.is_some_and(
+                |c| !c.is_whitespace() && !matches!(c, ')' | ']' | '}' | '>' | ',' | ';' | '.' | '?' | ':'),
+            );
+        let SegmentContent::Text(t) = &mut out.segs[seg_i.0].content else {
+            unreachable!();
+        };
+        if space_before {
+            t.insert(0, ' ');
+        }
+        if space_after {
+            t.push(' ');
+        }
+    }
 }
 
 pub(crate) fn has_comments(out: &mut MakeSegsState, t: impl ToTokens) -> bool {
-    t
-        .to_token_stream()
-        .into_iter()
-        .next()
-        .map(|t| out.whitespaces.contains_key(&HashLineColumn(t.span().start())))
+    t.to_token_stream().into_iter().next().map(|t| has_comments_at(out, t.span().start())).unwrap_or(false)
+}
+
+/// Whether there are comments before the token at `loc` that need their own lines
+/// (not inline block comments).
+pub(crate) fn has_comments_at(out: &MakeSegsState, loc: LineColumn) -> bool {
+    out
+        .whitespaces
+        .get(&HashLineColumn(loc))
+        .map(|ws| ws.iter().any(|w| !matches!(w.mode, WhitespaceMode::InlineBlockComment(_))))
         .unwrap_or(false)
 }
 
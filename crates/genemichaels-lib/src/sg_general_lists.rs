@@ -25,10 +25,10 @@ use {
         sg_general::{
             append_whitespace,
             has_comments,
+            has_comments_at,
         },
         SplitGroupIdx,
         new_sg,
-        whitespace::HashLineColumn,
     },
 };
 
@@ -155,7 +155,7 @@ pub(crate) fn append_bracketed_list<
     suffix_start: LineColumn,
     suffix: &str,
 ) {
-    if out.whitespaces.contains_key(&HashLineColumn(suffix_start)) {
+    if has_comments_at(out, suffix_start) {
         sg.initial_split();
     }
     append_whitespace(out, base_indent, sg, prefix_start);
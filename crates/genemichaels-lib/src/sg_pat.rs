@@ -5,6 +5,7 @@ use {
         sg_general::{
             append_bracketed_statement_list,
             append_whitespace,
+            has_comments_at,
             new_sg_binary,
             new_sg_macro,
             new_sg_outer_attrs,
@@ -24,7 +25,6 @@ use {
         },
         Alignment,
         Formattable,
-        HashLineColumn,
         MakeSegsState,
         SplitGroupIdx,
     },
@@ -86,7 +86,7 @@ impl Formattable for &Pat {
                                 let sg = &mut sg0;
                                 let prefix_start = t.paren_token.span.open().start();
                                 let suffix_start = t.paren_token.span.close().start();
-                                if out.whitespaces.contains_key(&HashLineColumn(suffix_start)) {
+                                if has_comments_at(out, suffix_start) {
                                     sg.initial_split();
                                 }
                                 append_whitespace(out, base_indent, sg, prefix_start);
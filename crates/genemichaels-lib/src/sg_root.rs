@@ -3,6 +3,7 @@ use {
         new_sg,
         sg_general::{
             append_statement_list_raw,
+            is_skip_attr,
             new_sg_outer_attrs,
         },
         Alignment,
@@ -10,7 +11,6 @@ use {
         MakeSegsState,
         SplitGroupIdx,
     },
-    quote::ToTokens,
     syn::{
         spanned::Spanned,
         AttrStyle,
@@ -46,13 +46,14 @@ impl Formattable for File {
                 if matches!(&attr.style, AttrStyle::Outer) {
                     continue;
                 }
-                if attr.meta.to_token_stream().to_string().contains("rustfmt :: skip") {
+                if is_skip_attr(attr) {
                     break 'res_rustfmt_skip self.span().source_text();
                 }
             }
             break 'res_rustfmt_skip None;
         } {
             out.whitespaces.clear();
+            out.line_end_comments.clear();
             let mut sg = new_sg(out);
             sg.seg(out, text);
             sg.build(out)
@@ -11,6 +11,7 @@ use {
             new_sg_binary,
             new_sg_block,
             new_sg_macro,
+            new_sg_skip_region,
             append_macro_bracketed,
         },
         sg_type::{
@@ -28,6 +29,7 @@ use {
         SplitGroupBuilder,
         check_split_brace_threshold,
         SplitGroupIdx,
+        HashLineColumn,
         sg_general_lists::{
             append_inline_list,
             append_bracketed_list,
@@ -1099,6 +1101,15 @@ impl Formattable for Item {
                 },
             ),
             Item::Verbatim(x) => {
+                let unparsed =
+                    x
+                        .clone()
+                        .into_iter()
+                        .next()
+                        .is_some_and(|t| out.unparsed.contains(&HashLineColumn(t.span().start())));
+                if unparsed {
+                    return new_sg_skip_region(out, base_indent, std::slice::from_ref(self));
+                }
                 let mut sg = new_sg(out);
                 append_macro_body(out, base_indent, &mut sg, x.clone());
                 sg.build(out)
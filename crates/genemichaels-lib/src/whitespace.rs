@@ -2,11 +2,25 @@ use {
     crate::{
         Comment,
         CommentMode,
+        CommentWrap,
+        FormatConfig,
+        MarkdownBullet,
+        MarkdownCodeFence,
+        MarkdownEmphasis,
+        MarkdownHeadings,
+        MarkdownOrderedList,
+        MarkdownOptions,
+        MarkdownStrong,
+        MarkdownStyle,
+        PreformattedDetection,
         Whitespace,
         WhitespaceMode,
     },
     loga::ea,
-    markdown::mdast::Node,
+    markdown::mdast::{
+        Node,
+        ReferenceKind,
+    },
     proc_macro2::{
         Group,
         LineColumn,
@@ -15,10 +29,14 @@ use {
     regex::Regex,
     std::{
         cell::RefCell,
-        collections::BTreeMap,
+        collections::{
+            BTreeMap,
+            BTreeSet,
+        },
         hash::Hash,
         rc::Rc,
         str::FromStr,
+        sync::LazyLock,
     },
 };
 
@@ -56,6 +74,18 @@ fn unicode_len(text: &str) -> VisualLen {
 pub fn extract_whitespaces(
     keep_max_blank_lines: usize,
     source: &str,
+) -> Result<(BTreeMap<HashLineColumn, Vec<Whitespace>>, TokenStream), loga::Error> {
+    extract_whitespaces_config(&FormatConfig {
+        keep_max_blank_lines: keep_max_blank_lines,
+        ..Default::default()
+    }, source)
+}
+
+/// Like `extract_whitespaces` but takes all extraction-related options from the
+/// config.
+pub fn extract_whitespaces_config(
+    config: &FormatConfig,
+    source: &str,
 ) -> Result<(BTreeMap<HashLineColumn, Vec<Whitespace>>, TokenStream), loga::Error> {
     let mut line_lookup = vec![];
     {
@@ -74,12 +104,17 @@ pub fn extract_whitespaces(
     struct State<'a> {
         source: &'a str,
         keep_max_blank_lines: usize,
+        keep_line_end_comments: bool,
+        keep_inline_block_comments: bool,
         // starting offset of each line
         line_lookup: Vec<usize>,
         whitespaces: BTreeMap<HashLineColumn, Vec<Whitespace>>,
         // records the beginning of the last line extracted - this is the destination for
         // transposed comments
         line_start: Option<LineColumn>,
+        // start of the last token extracted - this is the destination for line-end
+        // comments that aren't transposed
+        last_token_start: Option<LineColumn>,
         last_offset: usize,
         start_re: Option<Regex>,
         block_event_re: Option<Regex>,
@@ -237,6 +272,21 @@ pub fn extract_whitespaces(
                                         _ => unreachable!(),
                                     }
                                 };
+                                if self.keep_inline_block_comments && mode == CommentMode::Normal &&
+                                    !lines.contains('\n') &&
+                                    !text[next_start..].contains('\n') {
+                                    // Short comment inline before the next token, keep it there
+                                    buffer.flush();
+                                    buffer.out.push(Whitespace {
+                                        loc: buffer.loc,
+                                        mode: crate::WhitespaceMode::InlineBlockComment(Comment {
+                                            mode: mode,
+                                            lines: lines.trim().to_string(),
+                                        }),
+                                    });
+                                    text = &text[next_start..];
+                                    continue;
+                                }
                                 for line in lines.lines() {
                                     let mut line = line.trim();
                                     line = line.strip_prefix("* ").unwrap_or(line);
@@ -306,8 +356,28 @@ pub fn extract_whitespaces(
                     None => self.source.len(),
                 };
                 let text = &self.source[start .. eol];
-                if text.trim_start().starts_with("//") {
-                    self.add_comments(*previous_start, text);
+                let trimmed = text.trim_start();
+                if trimmed.starts_with("//") {
+                    // Plain `//` comments can stay at the end of the line, attached to the last
+                    // token
+                    let line_end_comment = match (self.keep_line_end_comments, self.last_token_start) {
+                        (true, Some(at)) => match trimmed[2..].chars().next() {
+                            Some('/' | '!' | '.' | '?') => None,
+                            _ => Some((at, trimmed[2..].trim())),
+                        },
+                        _ => None,
+                    };
+                    if let Some((at, lines)) = line_end_comment {
+                        self.whitespaces.entry(HashLineColumn(at)).or_insert(vec![]).push(Whitespace {
+                            loc: at,
+                            mode: WhitespaceMode::LineEndComment(Comment {
+                                mode: CommentMode::Normal,
+                                lines: lines.to_string(),
+                            }),
+                        });
+                    } else {
+                        self.add_comments(*previous_start, text);
+                    }
                 }
                 start = eol;
                 break true;
@@ -328,11 +398,14 @@ pub fn extract_whitespaces(
     // Extract comments
     let mut state = State {
         source: source,
-        keep_max_blank_lines: keep_max_blank_lines,
+        keep_max_blank_lines: config.keep_max_blank_lines,
+        keep_line_end_comments: config.keep_line_end_comments,
+        keep_inline_block_comments: config.keep_inline_block_comments,
         line_lookup: line_lookup,
         whitespaces: BTreeMap::new(),
         last_offset: 0usize,
         line_start: None,
+        last_token_start: None,
         start_re: None,
         block_event_re: None,
     };
@@ -345,9 +418,11 @@ pub fn extract_whitespaces(
                 proc_macro2::TokenTree::Group(g) => {
                     state.extract(state.last_offset, g.span_open().start());
                     state.last_offset = state.to_offset(g.span_open().end());
+                    state.last_token_start = Some(g.span_open().start());
                     let subtokens = recurse(state, g.stream());
                     state.extract(state.last_offset, g.span_close().start());
                     state.last_offset = state.to_offset(g.span_close().end());
+                    state.last_token_start = Some(g.span_close().start());
                     let mut new_g = Group::new(g.delimiter(), subtokens);
                     new_g.set_span(g.span());
                     out.push(proc_macro2::TokenTree::Group(new_g));
@@ -355,6 +430,7 @@ pub fn extract_whitespaces(
                 proc_macro2::TokenTree::Ident(g) => {
                     state.extract(state.last_offset, g.span().start());
                     state.last_offset = state.to_offset(g.span().end());
+                    state.last_token_start = Some(g.span().start());
                     out.push(proc_macro2::TokenTree::Ident(g));
                 },
                 proc_macro2::TokenTree::Punct(g) => {
@@ -373,12 +449,14 @@ pub fn extract_whitespaces(
                     } else {
                         state.extract(state.last_offset, g.span().start());
                         state.last_offset = state.to_offset(g.span().end());
+                        state.last_token_start = Some(g.span().start());
                         out.push(proc_macro2::TokenTree::Punct(g));
                     }
                 },
                 proc_macro2::TokenTree::Literal(g) => {
                     state.extract(state.last_offset, g.span().start());
                     state.last_offset = state.to_offset(g.span().end());
+                    state.last_token_start = Some(g.span().start());
                     out.push(proc_macro2::TokenTree::Literal(g));
                 },
             }
@@ -410,9 +488,12 @@ pub fn extract_whitespaces(
     Ok((state.whitespaces, tokens))
 }
 
-struct State {
+struct State<'a> {
     line_buffer: String,
     need_nl: bool,
+    style: &'a MarkdownStyle,
+    source: &'a str,
+    wrap: CommentWrap,
 }
 
 #[derive(Debug)]
@@ -664,6 +745,11 @@ impl LineState {
         self.write(state, out, text, &[]);
     }
 
+    /// End the current line if anything's been written to it.
+    fn flush(&self, state: &mut State, out: &mut String) {
+        self.0.as_ref().borrow_mut().flush(state, out, false, false);
+    }
+
     fn flush_always(&self, state: &mut State, out: &mut String) {
         self.0.as_ref().borrow_mut().flush_always(state, out, false, false);
     }
@@ -695,15 +781,82 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
         joined
     }
 
+    /// Code span with enough backticks to contain any backticks in the code.
+    fn inline_code(code: &str) -> String {
+        let mut longest = 0;
+        let mut run = 0;
+        for c in code.chars() {
+            if c == '`' {
+                run += 1;
+                longest = longest.max(run);
+            } else {
+                run = 0;
+            }
+        }
+        let fence = "`".repeat(longest + 1);
+        if code.starts_with('`') || code.ends_with('`') {
+            format!("{} {} {}", fence, code, fence)
+        } else {
+            format!("{}{}{}", fence, code, fence)
+        }
+    }
+
+    /// References as written, rather than the normalized (lowercase) identifier.
+    fn label<'a>(label: &'a Option<String>, identifier: &'a str) -> &'a str {
+        label.as_deref().unwrap_or(identifier)
+    }
+
+    /// The original markdown for a node, for details the AST doesn't keep.
+    fn source_of<'a>(state: &State<'a>, position: &Option<markdown::unist::Position>) -> &'a str {
+        match position {
+            Some(p) => &state.source[p.start.offset .. p.end.offset],
+            None => "",
+        }
+    }
+
+    /// Write text that may contain soft line breaks. In split-only mode the breaks
+    /// are kept, otherwise the lines are joined.
+    fn write_lines(state: &mut State, out: &mut String, line: &LineState, text: &str, breakable: bool) {
+        let text = match state.wrap {
+            CommentWrap::Reflow => join_lines(text),
+            CommentWrap::SplitOnly => text.to_string(),
+        };
+        let lines = Regex::new("\r?\n").unwrap().split(&text).collect::<Vec<&str>>();
+        for (i, l) in lines.iter().enumerate() {
+            let mut l = *l;
+            if i > 0 {
+                line.flush(state, out);
+                l = l.trim_start();
+            }
+            if i < lines.len() - 1 {
+                l = l.trim_end();
+            }
+            if breakable {
+                line.write_breakable(state, out, l);
+            } else {
+                line.write_unbreakable(state, out, l);
+            }
+        }
+    }
+
     match node {
         // block->block elements (newline between)
         Node::Root(x) => {
-            for (i, child) in x.children.iter().enumerate() {
+            // Link reference definitions are gathered at the end, in their original order
+            let (definitions, children): (Vec<&Node>, Vec<&Node>) =
+                x.children.iter().partition(|c| matches!(c, Node::Definition(_)));
+            for (i, child) in children.iter().enumerate() {
                 if i > 0 {
                     line.write_newline(state, out);
                 }
                 recurse_write(state, out, line.clone_zero_indent(), child, false);
             }
+            for (i, child) in definitions.iter().enumerate() {
+                if i == 0 && !children.is_empty() {
+                    line.write_newline(state, out);
+                }
+                recurse_write(state, out, line.clone_zero_indent(), child, false);
+            }
         },
         Node::Blockquote(x) => {
             let line = line.clone_indent(None, "> ".into(), false);
@@ -723,16 +876,24 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
                         if j > 0 {
                             line.write_newline(state, out);
                         }
+                        let number = match state.style.ordered_list {
+                            MarkdownOrderedList::Sequential => *i as usize + j,
+                            MarkdownOrderedList::Ones => 1,
+                        };
                         recurse_write(
                             state,
                             out,
-                            line.clone_indent(Some(format!("{}. ", *i as usize + j)), "   ".into(), false),
+                            line.clone_indent(Some(format!("{}. ", number)), "   ".into(), false),
                             child,
                             false,
                         );
                     }
                 },
                 None => {
+                    let bullet = match state.style.bullet {
+                        MarkdownBullet::Asterisk => "* ",
+                        MarkdownBullet::Dash => "- ",
+                    };
                     for (i, child) in x.children.iter().enumerate() {
                         if i > 0 {
                             line.write_newline(state, out);
@@ -740,7 +901,7 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
                         recurse_write(
                             state,
                             out,
-                            line.clone_indent(Some("* ".into()), "  ".into(), false),
+                            line.clone_indent(Some(bullet.into()), "  ".into(), false),
                             child,
                             false,
                         );
@@ -758,7 +919,21 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
         },
         // block->inline elements (flush after)
         Node::Code(x) => {
-            line.write_unbreakable(state, out, &format!("```{}", match &x.lang {
+            let fence_char = match state.style.code_fence {
+                MarkdownCodeFence::Backticks => '`',
+                MarkdownCodeFence::Tildes => '~',
+            };
+
+            // The fence must be longer than any fence-like line in the code
+            let inner_fence =
+                x
+                    .value
+                    .lines()
+                    .map(|l| l.trim_start().chars().take_while(|c| *c == fence_char).count())
+                    .max()
+                    .unwrap_or(0);
+            let fence = fence_char.to_string().repeat(state.style.code_fence_length.max(3).max(inner_fence + 1));
+            line.write_unbreakable(state, out, &format!("{}{}", fence, match &x.lang {
                 None => "",
                 Some(x) => x,
             }));
@@ -767,7 +942,40 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
                 line.write_unbreakable(state, out, l);
                 line.flush_always(state, out);
             }
-            line.write_unbreakable(state, out, "```");
+            line.write_unbreakable(state, out, &fence);
+            line.flush_always(state, out);
+        },
+        Node::Math(x) => {
+            // Display math, kept as-is with the original fence length
+            let fence = "$".repeat(source_of(state, &x.position).chars().take_while(|c| *c == '$').count().max(2));
+            line.write_unbreakable(state, out, &format!("{}{}", fence, match &x.meta {
+                None => "",
+                Some(x) => x,
+            }));
+            line.flush_always(state, out);
+            for l in x.value.as_str().lines() {
+                line.write_unbreakable(state, out, l);
+                line.flush_always(state, out);
+            }
+            line.write_unbreakable(state, out, &fence);
+            line.flush_always(state, out);
+        },
+        Node::Heading(x) if state.style.headings == MarkdownHeadings::Keep && x.depth <= 2 &&
+            x.position.as_ref().is_some_and(|p| !state.source[p.start.offset..].starts_with('#')) => {
+            // Setext heading, underlined to the width of the text
+            let start = out.len();
+            for child in &x.children {
+                recurse_write(state, out, line.clone_inline(), child, true);
+            }
+            line.flush_always(state, out);
+            let prefix_len = unicode_len(&line.0.as_ref().borrow().prefix).0;
+            let width =
+                out[start..].lines().map(|l| unicode_len(l).0.saturating_sub(prefix_len)).max().unwrap_or(0);
+            line.write_unbreakable(state, out, &if x.depth == 1 {
+                "="
+            } else {
+                "-"
+            }.repeat(width.max(3)));
             line.flush_always(state, out);
         },
         Node::Heading(x) => {
@@ -778,7 +986,7 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
             line.flush_always(state, out);
         },
         Node::FootnoteDefinition(x) => {
-            let line = line.clone_indent(Some(format!("[^{}]: ", x.identifier)), "   ".into(), false);
+            let line = line.clone_indent(Some(format!("[^{}]: ", label(&x.label, &x.identifier))), "   ".into(), false);
             for child in &x.children {
                 recurse_write(state, out, line.clone_inline(), child, true);
             }
@@ -789,7 +997,7 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
             line.flush_always(state, out);
         },
         Node::Definition(x) => {
-            line.write_unbreakable(state, out, &format!("[{}]: {}", x.identifier.trim(), x.url));
+            line.write_unbreakable(state, out, &format!("[{}]: {}", label(&x.label, &x.identifier).trim(), x.url));
             if let Some(title) = &x.title {
                 line.write_unbreakable(state, out, " \"");
                 line.write_breakable(state, out, title);
@@ -809,17 +1017,25 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
         },
         // inline elements
         Node::Text(x) => {
-            line.write_breakable(state, out, &join_lines(&x.value));
+            write_lines(state, out, &line, &x.value, true);
         },
         Node::InlineCode(x) => {
-            line.write_unbreakable(state, out, &format!("`{}`", join_lines(&x.value)));
+            write_lines(state, out, &line, &inline_code(&x.value), false);
+        },
+        Node::InlineMath(x) => {
+            let fence = "$".repeat(source_of(state, &x.position).chars().take_while(|c| *c == '$').count().max(1));
+            line.write_unbreakable(state, out, &format!("{}{}{}", fence, join_lines(&x.value), fence));
         },
         Node::Strong(x) => {
-            line.write_unbreakable(state, out, "**");
+            let marker = match state.style.strong {
+                MarkdownStrong::Asterisks => "**",
+                MarkdownStrong::Underscores => "__",
+            };
+            line.write_unbreakable(state, out, marker);
             for child in &x.children {
                 recurse_write(state, out, line.clone_inline(), child, true);
             }
-            line.write_unbreakable(state, out, "**");
+            line.write_unbreakable(state, out, marker);
         },
         Node::Delete(x) => {
             line.write_unbreakable(state, out, "~~");
@@ -829,14 +1045,18 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
             line.write_unbreakable(state, out, "~~");
         },
         Node::Emphasis(x) => {
-            line.write_unbreakable(state, out, "_");
+            let marker = match state.style.emphasis {
+                MarkdownEmphasis::Underscore => "_",
+                MarkdownEmphasis::Asterisk => "*",
+            };
+            line.write_unbreakable(state, out, marker);
             for child in &x.children {
                 recurse_write(state, out, line.clone_inline(), child, true);
             }
-            line.write_unbreakable(state, out, "_");
+            line.write_unbreakable(state, out, marker);
         },
         Node::FootnoteReference(x) => {
-            line.write_unbreakable(state, out, &format!("[^{}]", x.identifier));
+            line.write_unbreakable(state, out, &format!("[^{}]", label(&x.label, &x.identifier)));
         },
         Node::Html(x) => {
             line.write_unbreakable(state, out, &format!("`{}`", join_lines(&x.value)));
@@ -869,7 +1089,7 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
             }
         },
         Node::ImageReference(x) => {
-            line.write_unbreakable(state, out, &format!("![][{}]", x.identifier));
+            line.write_unbreakable(state, out, &format!("![][{}]", label(&x.label, &x.identifier)));
         },
         Node::Link(x) => {
             let simple_text = if x.children.len() != 1 {
@@ -890,7 +1110,7 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
                     if get_splits(&t).first().is_some() {
                         None
                     } else {
-                        Some(format!("`{}`", t))
+                        Some(inline_code(&t))
                     }
                 },
                 _ => None,
@@ -942,14 +1162,24 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
                 Node::InlineCode(t) => if get_splits(&t.value).first().is_some() {
                     None
                 } else {
-                    Some(format!("`{}`", t.value))
+                    Some(inline_code(&t.value))
                 },
                 _ => {
                     None
                 },
             });
+            // The parsed label has markup removed and is normalized, so use the original
+            let source = source_of(state, &x.position);
+            let label = join_lines(match x.reference_kind {
+                ReferenceKind::Full => match source.rfind("][") {
+                    Some(i) if source.ends_with(']') => &source[i + 2 .. source.len() - 1],
+                    _ => &x.identifier,
+                },
+                ReferenceKind::Collapsed => source.strip_prefix('[').and_then(|s| s.strip_suffix("][]")).unwrap_or(&x.identifier),
+                ReferenceKind::Shortcut => source.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(&x.identifier),
+            });
             match simple_text {
-                Some(t) if t == x.identifier => {
+                Some(t) if t == label => {
                     line.write_unbreakable(state, out, &format!("[{}]", t));
                 },
                 _ => {
@@ -957,14 +1187,16 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
                     for child in &x.children {
                         recurse_write(state, out, line.clone_inline(), child, true);
                     }
-                    line.write_unbreakable(state, out, &format!("][{}]", x.identifier));
+                    line.write_unbreakable(state, out, &format!("][{}]", label));
                 },
             }
         },
         Node::Break(_) => {
-            // normalized out
+            // normalized out, or a normal line break if keeping line breaks
+            if state.wrap == CommentWrap::SplitOnly {
+                line.flush(state, out);
+            }
         },
-        Node::Math(_) => unreachable!(),
         Node::Table(_) => unreachable!(),
         Node::TableRow(_) => unreachable!(),
         Node::TableCell(_) => unreachable!(),
@@ -974,17 +1206,203 @@ fn recurse_write(state: &mut State, out: &mut String, line: LineState, node: &No
         Node::MdxjsEsm(_) => unreachable!(),
         Node::Toml(_) => unreachable!(),
         Node::Yaml(_) => unreachable!(),
-        Node::InlineMath(_) => unreachable!(),
         Node::MdxTextExpression(_) => unreachable!(),
     }
 }
 
+/// Whether comment text looks like a diagram, table, or other preformatted text
+/// that would be mangled by formatting it as markdown.
+pub fn looks_preformatted(text: &str, detection: &PreformattedDetection) -> bool {
+    // Skip code blocks, which are kept as-is anyway, and markdown rules/underlines
+    let mut lines = vec![];
+    let mut fence: Option<String> = None;
+    for line in text.lines() {
+        let line = line.strip_prefix(' ').unwrap_or(line);
+        let trimmed = line.trim();
+        if let Some(f) = &fence {
+            if trimmed.starts_with(f.as_str()) {
+                fence = None;
+            }
+            lines.push("");
+            continue;
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") || trimmed.starts_with("$$") {
+            let c = trimmed.chars().next().unwrap();
+            fence = Some(trimmed.chars().take_while(|x| *x == c).collect());
+            lines.push("");
+            continue;
+        }
+        if line.starts_with("    ") || line.starts_with('\t') {
+            lines.push("");
+            continue;
+        }
+        let mut chars = trimmed.chars().filter(|c| *c != ' ');
+        if let Some(c) = chars.next() {
+            if "-=*_".contains(c) && chars.all(|x| x == c) {
+                lines.push("");
+                continue;
+            }
+        }
+        lines.push(line);
+    }
+    let min_lines = detection.min_lines.max(1);
+    if detection.box_drawing {
+        let count = lines.iter().filter(|l| {
+            let t = l.trim();
+            l.chars().filter(|c| ('\u{2500}' ..= '\u{259f}').contains(c)).count() >= 2 ||
+                t.len() >= 3 && (t.starts_with('|') || t.starts_with('+')) && (t.ends_with('|') || t.ends_with('+')) ||
+                t.contains("+--") ||
+                t.contains("--+")
+        }).count();
+        if count >= min_lines {
+            return true;
+        }
+    }
+    if detection.aligned_columns {
+        // Columns start after a run of 2+ spaces (not counting indentation). Look for
+        // consecutive lines with a column in the same place.
+        let mut run = 0usize;
+        let mut prev_columns = BTreeSet::new();
+        for l in &lines {
+            let mut columns = BTreeSet::new();
+            let mut spaces = 0usize;
+            let mut seen_text = false;
+            for (i, c) in l.chars().enumerate() {
+                if c == ' ' {
+                    spaces += 1;
+                    continue;
+                }
+                if seen_text && spaces >= 2 {
+                    columns.insert(i);
+                }
+                seen_text = true;
+                spaces = 0;
+            }
+            if columns.is_empty() {
+                run = 0;
+            } else if columns.intersection(&prev_columns).next().is_some() {
+                run += 1;
+            } else {
+                run = 1;
+            }
+            if run >= min_lines.max(2) {
+                return true;
+            }
+            prev_columns = columns;
+        }
+    }
+    if let Some(ratio) = detection.punctuation_ratio {
+        let count = lines.iter().filter(|l| {
+            let non_space = l.chars().filter(|c| !c.is_whitespace()).count();
+            let punct = l.chars().filter(|c| !c.is_whitespace() && !c.is_alphanumeric()).count();
+            non_space >= 3 && punct as f64 >= ratio * non_space as f64
+        }).count();
+        if count >= min_lines {
+            return true;
+        }
+    }
+    false
+}
+
+fn parse_md(source: &str) -> Result<Node, loga::Error> {
+    markdown::to_mdast(source, &markdown::ParseOptions {
+        constructs: markdown::Constructs {
+            math_flow: true,
+            math_text: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    }).map_err(|e| loga::err_with("Error parsing markdown", ea!(err = e)))
+}
+
+/// Targets of rustdoc intra-doc links in doc comment markdown: link and definition
+/// destinations that are Rust paths, plus shortcut links (`[Foo]`, ``[`Foo`]``)
+/// with no definition. Disambiguators (`struct@`), generics and `()`/`!` suffixes
+/// are removed.
+pub fn intra_doc_link_targets(source: &str) -> Result<Vec<String>, loga::Error> {
+    fn path(text: &str) -> Option<String> {
+        let text = text.trim();
+        let text = match text.split_once('@') {
+            Some((d, t)) if d.chars().all(|c| c.is_ascii_lowercase()) => t,
+            _ => text,
+        };
+        let text = text.strip_suffix("()").or_else(|| text.strip_suffix('!')).unwrap_or(text);
+        let text = match text.find('<') {
+            Some(i) if text.ends_with('>') => &text[..i],
+            _ => text,
+        };
+        static PATH_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*(::[A-Za-z_][A-Za-z0-9_]*)*$").unwrap());
+        if !PATH_RE.is_match(text) {
+            return None;
+        }
+        Some(text.to_string())
+    }
+
+    fn walk(node: &Node, out: &mut Vec<String>) {
+        match node {
+            Node::Link(x) => out.extend(path(&x.url)),
+            Node::Definition(x) => out.extend(path(&x.url)),
+            _ => { },
+        }
+        let Some(children) = node.children() else {
+            return;
+        };
+        static SHORTCUT_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"(?:^|[^\w\]\\])\[([^\[\]\s]+)\](?:$|[^(\[:])").unwrap());
+        for (i, child) in children.iter().enumerate() {
+            match child {
+                Node::Text(x) => {
+                    for m in SHORTCUT_RE.captures_iter(&x.value) {
+                        out.extend(path(&m[1]));
+                    }
+                },
+                Node::InlineCode(x) => {
+                    // Unresolved ``[`Foo`]`` is parsed as text around inline code
+                    let before = i.checked_sub(1).and_then(|j| children.get(j));
+                    let after = children.get(i + 1);
+                    if matches!(before, Some(Node::Text(t)) if t.value.ends_with('[')) &&
+                        matches!(after, Some(Node::Text(t)) if t.value.starts_with(']') && !t.value[1..].starts_with(['(', '[', ':'])) {
+                        out.extend(path(&x.value));
+                    }
+                },
+                _ => { },
+            }
+            walk(child, out);
+        }
+    }
+
+    let mut out = vec![];
+    walk(&parse_md(source)?, &mut out);
+    Ok(out)
+}
+
 pub fn format_md(
     true_out: &mut String,
     max_width: usize,
     rel_max_width: Option<usize>,
     prefix: &str,
     source: &str,
+) -> Result<(), loga::Error> {
+    format_md_styled(
+        true_out,
+        max_width,
+        rel_max_width,
+        prefix,
+        source,
+        &MarkdownOptions::default(),
+    )
+}
+
+/// Like `format_md` but with options like the choice of markdown syntax for
+/// constructs with multiple forms, and whether to keep existing line breaks.
+pub fn format_md_styled(
+    true_out: &mut String,
+    max_width: usize,
+    rel_max_width: Option<usize>,
+    prefix: &str,
+    source: &str,
+    options: &MarkdownOptions,
 ) -> Result<(), loga::Error> {
     // TODO, due to a bug a bunch of unreachable branches might have had code added.
     // I'd like to go back and see if some block-level starts can be removed in
@@ -994,11 +1412,11 @@ pub fn format_md(
         let mut state = State {
             line_buffer: String::new(),
             need_nl: false,
+            style: &options.style,
+            source: source,
+            wrap: options.wrap,
         };
-        let ast = markdown::to_mdast(source, &markdown::ParseOptions {
-            constructs: markdown::Constructs { ..Default::default() },
-            ..Default::default()
-        }).map_err(|e| loga::err_with("Error parsing markdown", ea!(err = e)))?;
+        let ast = parse_md(source)?;
         recurse_write(
             &mut state,
             &mut out,
@@ -5,6 +5,17 @@ use {
         CommentMode,
         HashLineColumn,
         format_md,
+        format_md_styled,
+        intra_doc_link_targets,
+        CommentWrap,
+        MarkdownBullet,
+        MarkdownCodeFence,
+        MarkdownEmphasis,
+        MarkdownHeadings,
+        MarkdownOptions,
+        MarkdownOrderedList,
+        MarkdownStrong,
+        MarkdownStyle,
         Whitespace,
     },
     proc_macro2::LineColumn,
@@ -25,6 +36,12 @@ fn eq_whitespace_text(w: &Vec<Whitespace>) -> String {
             genemichaels_lib::WhitespaceMode::Comment(c) => {
                 text.push_str(&format!("comment {:?} [{:?}]\n", c.mode, c.lines));
             },
+            genemichaels_lib::WhitespaceMode::LineEndComment(c) => {
+                text.push_str(&format!("line end comment {:?} [{:?}]\n", c.mode, c.lines));
+            },
+            genemichaels_lib::WhitespaceMode::InlineBlockComment(c) => {
+                text.push_str(&format!("inline block comment {:?} [{:?}]\n", c.mode, c.lines));
+            },
         }
     }
     return text;
@@ -176,3 +193,101 @@ fn format_overflow_use_next_break1() {
     format_md(&mut res, 0, Some(10), "// ", "`abcd abcd` a b c d").unwrap();
     assert_eq!(res, "// `abcd abcd`\n// a b c d");
 }
+
+#[test]
+fn format_md_default_style() {
+    let mut res = String::new();
+    format_md(&mut res, 1000, None, "// ", "- a *b* __c__\n\n3. e\n4. f\n\nG\n=\n\n~~~\nx\n~~~").unwrap();
+    assert_eq!(res, "// * a _b_ **c**\n//\n// 3. e\n//\n// 4. f\n//\n// # G\n//\n// ```\n// x\n// ```");
+}
+
+#[test]
+fn format_md_custom_style() {
+    let mut res = String::new();
+    format_md_styled(
+        &mut res,
+        1000,
+        None,
+        "// ",
+        "* a _b_ **c**\n\n3. e\n4. f\n\nLong heading\n---\n\n# H\n\n```\nx\n```",
+        &MarkdownOptions {
+            style: MarkdownStyle {
+                bullet: MarkdownBullet::Dash,
+                emphasis: MarkdownEmphasis::Asterisk,
+                strong: MarkdownStrong::Underscores,
+                ordered_list: MarkdownOrderedList::Ones,
+                headings: MarkdownHeadings::Keep,
+                code_fence: MarkdownCodeFence::Tildes,
+                code_fence_length: 4,
+            },
+            ..Default::default()
+        },
+    ).unwrap();
+    assert_eq!(
+        res,
+        "// - a *b* __c__\n//\n// 1. e\n//\n// 1. f\n//\n// Long heading\n// ------------\n//\n// # H\n//\n// ~~~~\n// x\n// ~~~~"
+    );
+}
+
+#[test]
+fn format_md_code_fence_lengthened() {
+    let mut res = String::new();
+    format_md(&mut res, 1000, None, "", "````md\n```\nx\n```\n````").unwrap();
+    assert_eq!(res, "````md\n```\nx\n```\n````");
+}
+
+#[test]
+fn format_md_split_only_keeps_lines() {
+    let mut res = String::new();
+    format_md_styled(
+        &mut res,
+        0,
+        Some(20),
+        "// ",
+        "One sentence.\nAnother sentence that is long `a b c`\nand [a link](x).",
+        &MarkdownOptions {
+            wrap: CommentWrap::SplitOnly,
+            ..Default::default()
+        },
+    ).unwrap();
+    assert_eq!(res, "// One sentence.\n// Another sentence\n// that is long\n// `a b c`\n// and [a link](x).");
+}
+
+#[test]
+fn format_md_math() {
+    let mut res = String::new();
+    format_md(
+        &mut res,
+        0,
+        Some(24),
+        "/// ",
+        "Computes $x_i^2 + y_i^2$ for each\npoint, then:\n\n$$\n\\sum_{i=0}^{n} a_i * b_i\n$$",
+    ).unwrap();
+    assert_eq!(res, "/// Computes\n/// $x_i^2 + y_i^2$ for\n/// each point, then:\n///\n/// $$\n/// \\sum_{i=0}^{n} a_i * b_i\n/// $$");
+}
+
+#[test]
+fn format_md_definitions_last() {
+    let mut res = String::new();
+    format_md(
+        &mut res,
+        120,
+        None,
+        "/// ",
+        "See [`Foo::bar`], [the method][Foo::bar] and [a *b* c] (or ``[`x`]``).\n\n[Foo::bar]: crate::Foo::bar\n\nMore [`Baz`].\n\n[a *b* c]: Baz",
+    ).unwrap();
+    assert_eq!(
+        res,
+        "/// See [`Foo::bar`], [the method][Foo::bar] and [a _b_ c][a *b* c] (or ``[`x`]``).\n///\n/// More [`Baz`].\n///\n/// [Foo::bar]: crate::Foo::bar\n/// [a *b* c]: Baz",
+    );
+}
+
+#[test]
+fn intra_doc_links() {
+    assert_eq!(
+        intra_doc_link_targets(
+            "Uses [`Foo::bar()`], [Baz], [`Vec<u8>`] and [x](struct@Qux) but not array[i], [y](https://a.b) or `[Nope]`.",
+        ).unwrap(),
+        vec!["Foo::bar".to_string(), "Baz".to_string(), "Vec".to_string(), "Qux".to_string()],
+    );
+}
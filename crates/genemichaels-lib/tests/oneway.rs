@@ -1,7 +1,14 @@
 use {
     genemichaels_lib::{
         FormatConfig,
+        FragmentKind,
         format_str,
+        format_fragment_str,
+        format_str_recover,
+        format_tokens,
+        MacroStrategy,
+        NewlineStyle,
+        PreformattedDetection,
     },
 };
 
@@ -125,3 +132,539 @@ fn ow_rustfmt_skip_end_line_end_comment_keep() {
         },
     );
 }
+
+fn macros_config(macros: &[(&str, MacroStrategy)]) -> FormatConfig {
+    FormatConfig {
+        max_width: 120,
+        macros: macros.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn ow_macro_verbatim() {
+    owc(
+        r#"fn main() {
+    let x = html! {
+        <div   class="a">  // keep me
+            { x +1 }
+        </div>
+    };
+}
+"#,
+        r#"fn main() {
+    let x = html!{
+        <div   class="a">  // keep me
+            { x +1 }
+        </div>
+    };
+}
+"#,
+        &macros_config(&[("html", MacroStrategy::Verbatim)]),
+    );
+}
+
+#[test]
+fn ow_macro_verbatim_full_path() {
+    owc(
+        r#"fn main() {
+    sqlx::query!("select *  from x where a = ?",   a);
+    other::query!("select *  from x where a = ?",   a);
+}
+"#,
+        r#"fn main() {
+    sqlx::query!("select *  from x where a = ?",   a);
+    other::query!("select *  from x where a = ?", a);
+}
+"#,
+        &macros_config(&[("sqlx::query", MacroStrategy::Verbatim)]),
+    );
+}
+
+#[test]
+fn ow_macro_items() {
+    owc(
+        r#"my_items!{
+    struct A;
+    fn b() {}
+}
+"#,
+        r#"my_items!{
+    struct A;
+
+    fn b() { }
+}
+"#,
+        &macros_config(&[("my_items", MacroStrategy::Items)]),
+    );
+}
+
+#[test]
+fn ow_macro_freeform() {
+    owc(r#"fn main() {
+    m!(|x| x, c);
+}
+"#, r#"fn main() {
+    m!(| x | x, c);
+}
+"#, &macros_config(&[("m", MacroStrategy::Freeform)]));
+}
+
+/// Check the text from the `off` marker to the `on` marker (or the end) is passed
+/// through byte for byte.
+fn skip_region_identical(before: &str, want_after: &str) {
+    ow(before, want_after);
+    let start = before.find("// genemichaels: off").unwrap();
+    let end = match before.find("// genemichaels: on") {
+        Some(at) => at + "// genemichaels: on".len(),
+        None => before.len(),
+    };
+    let after = format_str(before, &FormatConfig {
+        max_width: 120,
+        ..Default::default()
+    }).unwrap().rendered;
+    assert!(after.contains(&before[start .. end]), "{}", after);
+}
+
+#[test]
+fn ow_skip_region() {
+    skip_region_identical(r#"fn main() {
+    let a  =  1;
+    // genemichaels: off
+    let matrix = [
+        1, 0,
+        0, 1,
+    ];
+
+    // Aligned
+    let b    = 2; // trailing
+    // genemichaels: on
+    let c  =  3;
+}
+"#, r#"fn main() {
+    let a = 1;
+    // genemichaels: off
+    let matrix = [
+        1, 0,
+        0, 1,
+    ];
+
+    // Aligned
+    let b    = 2; // trailing
+    // genemichaels: on
+    let c = 3;
+}
+"#);
+}
+
+#[test]
+fn ow_skip_region_marker_comments() {
+    skip_region_identical(r#"fn main() {
+    let a  =  1;
+    // Identity
+    // genemichaels: off
+    let matrix = [
+        1, 0,
+        0, 1,
+    ];
+    // genemichaels: on
+    // Back to normal
+    let c  =  3;
+}
+"#, r#"fn main() {
+    let a = 1;
+
+    // Identity
+    // genemichaels: off
+    let matrix = [
+        1, 0,
+        0, 1,
+    ];
+    // genemichaels: on
+
+    // Back to normal
+    let c = 3;
+}
+"#);
+}
+
+#[test]
+fn ow_skip_region_items_to_end() {
+    skip_region_identical(r#"const A: u8 =   1;
+// genemichaels: off
+const B: u8 =   2;
+
+const C: u8 =   3;
+"#, r#"const A: u8 = 1;
+// genemichaels: off
+const B: u8 =   2;
+
+const C: u8 =   3;
+"#);
+}
+
+#[test]
+fn ow_skip_attr() {
+    ow(r#"#[genemichaels::skip]
+const A: u8 =   1;
+#[cfg_attr(any(), genemichaels::skip)]
+const B: u8 =   2;
+const C: u8 =   3;
+"#, r#"#[genemichaels::skip]
+const A: u8 =   1;
+#[cfg_attr(any(), genemichaels::skip)]
+const B: u8 =   2;
+const C: u8 = 3;
+"#);
+}
+
+fn line_end_config(max_width: usize) -> FormatConfig {
+    FormatConfig {
+        max_width: max_width,
+        keep_line_end_comments: true,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn ow_line_end_comment_kept() {
+    owc(r#"fn main() {
+    let x = 1; // one
+    if x { // cond
+        bar();
+    }
+}
+"#, r#"fn main() {
+    let x = 1; // one
+    if x { // cond
+        bar();
+    }
+}
+"#, &line_end_config(120));
+}
+
+#[test]
+fn ow_line_end_comment_splits_list() {
+    owc(r#"fn main() {
+    let y = foo(a, // first arg
+        b);
+}
+"#, r#"fn main() {
+    let y = foo(
+        a, // first arg
+        b,
+    );
+}
+"#, &line_end_config(120));
+}
+
+#[test]
+fn ow_line_end_comment_too_long() {
+    owc(r#"fn main() {
+    let abc = 1; // this comment doesn't fit
+}
+"#, r#"fn main() {
+    // this comment doesn't fit
+    let abc = 1;
+}
+"#, &line_end_config(30));
+}
+
+#[test]
+fn ow_inline_block_comments() {
+    owc(r#"fn main() {
+    foo(/* verbose */ true, /* retries */ 3);
+    bar(x /* unused */);
+    baz(/* nothing */);
+    qux(a /* first */, b);
+    /* header */
+    let x = 1;
+}
+"#, r#"fn main() {
+    foo(/* verbose */ true, /* retries */ 3);
+    bar(x /* unused */);
+    baz(/* nothing */);
+    qux(a /* first */, b);
+
+    // header
+    let x = 1;
+}
+"#, &FormatConfig {
+        keep_inline_block_comments: true,
+        ..Default::default()
+    });
+}
+
+#[test]
+fn ow_inline_block_comments_binary() {
+    owc(r#"fn main() {
+    let y = x /* c */ + 1;
+    let z = x + /* one */ 1 /* two */ * /* three */ 2;
+    let é = "é" /* ü */ == "ü";
+}
+"#, r#"fn main() {
+    let y = x /* c */ + 1;
+    let z = x + /* one */ 1 /* two */ * /* three */ 2;
+    let é = "é" /* ü */ == "ü";
+}
+"#, &FormatConfig {
+        keep_inline_block_comments: true,
+        ..Default::default()
+    });
+}
+
+#[test]
+fn ow_inline_block_comments_method_chain() {
+    owc(r#"fn main() {
+	let y = x /* c */ .foo() /* d */ .bar(/* e */);
+    let z = x.foo(a /* arg */)?;
+}
+"#, r#"fn main() {
+    let y = x /* c */.foo() /* d */.bar(/* e */);
+    let z = x.foo(a /* arg */)?;
+}
+"#, &FormatConfig {
+        keep_inline_block_comments: true,
+        ..Default::default()
+    });
+}
+
+#[test]
+fn ow_recover_syntax_errors() {
+    let res = format_str_recover(r#"fn a() { let x =   1; }
+
+// Broken
+fn b() { let = ; }
+
+struct   C;
+"#, &FormatConfig::default()).unwrap();
+    assert_eq!(res.rendered, r#"fn a() {
+    let x = 1;
+}
+
+// Broken
+fn b() { let = ; }
+
+struct C;
+"#);
+    assert_eq!(res.warnings.len(), 1);
+    assert_eq!(res.unparsed_items, 1);
+
+    // Other warnings don't count as unparsed
+    let res = format_str_recover("// +---+\n// | a |\n// +---+\nfn a() { }\n", &FormatConfig {
+        detect_preformatted: Some(PreformattedDetection::default()),
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(res.warnings.len(), 1);
+    assert_eq!(res.unparsed_items, 0);
+}
+
+#[test]
+fn ow_format_tokens_generated() {
+    let tokens = quote::quote! {
+        #[doc = " Generated."]
+        #[doc = " Second line."]
+        pub struct Foo { pub a: u8, pub b: u8 }
+        impl Foo { pub fn new() -> Self { Foo { a: 1, b: 2 } } }
+    };
+    let res = format_tokens(tokens, &FormatConfig::default(), true).unwrap();
+    assert_eq!(res.rendered, r#"/// Generated. Second line.
+pub struct Foo {
+    pub a: u8,
+    pub b: u8,
+}
+
+impl Foo {
+    pub fn new() -> Self {
+        Foo {
+            a: 1,
+            b: 2,
+        }
+    }
+}
+"#);
+}
+
+#[test]
+fn ow_fragment_expr() {
+    let res = format_fragment_str("foo(a,b)  + 1", FragmentKind::Expr, 1, &FormatConfig::default()).unwrap();
+    assert_eq!(res.rendered, "    foo(a, b) + 1\n");
+}
+
+#[test]
+fn ow_fragment_stmts() {
+    let res =
+        format_fragment_str(
+            "let x=1;\n// Comment\nif x { y(); }",
+            FragmentKind::Stmts,
+            2,
+            &FormatConfig::default(),
+        ).unwrap();
+    assert_eq!(res.rendered, r#"        let x = 1;
+
+        // Comment
+        if x {
+            y();
+        }
+"#);
+}
+
+#[test]
+fn ow_fragment_impl_items() {
+    let res =
+        format_fragment_str(
+            "fn a(&self) {}\nconst B: u8=1;",
+            FragmentKind::ImplItems,
+            0,
+            &FormatConfig::default(),
+        ).unwrap();
+    assert_eq!(res.rendered, "fn a(&self) { }\n\nconst B: u8 = 1;\n");
+}
+
+#[test]
+fn ow_position_map() {
+    let source = "fn main(){let   xyz=foo(a,b);}\n";
+    assert!(format_str(source, &FormatConfig::default()).unwrap().position_map.is_empty());
+    let res = format_str(source, &FormatConfig {
+        position_map: true,
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(res.rendered, "fn main() {\n    let xyz = foo(a, b);\n}\n");
+    let input = source.find("xyz").unwrap() + 1;
+    let output = res.rendered.find("xyz").unwrap() + 1;
+    assert_eq!(res.map_offset(input), output);
+    let input = source.find("b)").unwrap();
+    let output = res.rendered.find("b)").unwrap();
+    assert_eq!(res.map_offset(input), output);
+}
+
+#[test]
+fn ow_position_map_doc_comments() {
+    let source =
+        "//! Crate docs.\n\n/// First.\n/// Second,\n/// third.\n///\n/// More.\nfn target_fn(){let   qq=1;}\n";
+    let res = format_str(source, &FormatConfig {
+        position_map: true,
+        ..Default::default()
+    }).unwrap();
+    for name in ["target_fn", "qq"] {
+        let input = source.find(name).unwrap() + 1;
+        let output = res.rendered.find(name).unwrap() + 1;
+        assert_eq!(res.map_offset(input), output, "{}", name);
+    }
+}
+
+#[test]
+fn ow_edits() {
+    let source = "fn main() {\n    let   x = foo(a,b);\n    let y = 2;\n}\n";
+    let res = format_str(source, &FormatConfig::default()).unwrap();
+    let edits = res.edits(source);
+    assert_eq!(edits.len(), 2);
+    let mut applied = source.to_string();
+    for edit in edits.iter().rev() {
+        applied.replace_range(edit.range.clone(), &edit.replacement);
+    }
+    assert_eq!(applied, res.rendered);
+}
+
+#[test]
+fn ow_crlf_bom_preserved() {
+    let source = "\u{feff}fn main(){\r\n    let   x = 1;\r\n}\r\n";
+    let res = format_str(source, &FormatConfig {
+        position_map: true,
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(res.rendered, "\u{feff}fn main() {\r\n    let x = 1;\r\n}\r\n");
+    let input = source.find('x').unwrap();
+    let output = res.rendered.find('x').unwrap();
+    assert_eq!(res.map_offset(input), output);
+}
+
+#[test]
+fn ow_newline_style_lf() {
+    let res = format_str("fn main(){\r\n}\r\n", &FormatConfig {
+        newline_style: NewlineStyle::Lf,
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(res.rendered, "fn main() { }\n");
+}
+
+#[test]
+fn ow_preformatted_comment_kept() {
+    let source = r#"// Layout:
+//
+// +------+     +------+
+// | a    | --> | b    |
+// +------+     +------+
+fn main() { }
+
+// name    value
+// foo     1
+// barbaz  2
+fn b() { }
+"#;
+    let res = format_str(source, &FormatConfig {
+        detect_preformatted: Some(PreformattedDetection::default()),
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(res.rendered, source);
+    assert_eq!(res.warnings.len(), 2);
+}
+
+#[test]
+fn ow_preformatted_detection_disabled() {
+    owc(r#"// name    value
+// foo     1
+fn main() { }
+"#, r#"// name    value foo     1
+fn main() { }
+"#, &FormatConfig {
+        detect_preformatted: None,
+        ..Default::default()
+    });
+}
+
+#[test]
+fn ow_check_intra_doc_links() {
+    let source = r#"use std::fmt::Display;
+
+/// Wraps [`Inner`], see [`Foo::bar`], [`Display`], [`std::io::Read`] and
+/// [`Missing`].
+pub struct Foo {
+    inner: Inner,
+}
+
+impl Foo {
+    /// Returns [`Self::inner`] or [`Foo::nothing`], never [`None`] or [`Vec`].
+    pub fn bar(&self) -> &Inner {
+        &self.inner
+    }
+}
+pub struct Inner;
+"#;
+    let res = format_str(source, &FormatConfig {
+        check_intra_doc_links: true,
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(res.rendered, source);
+    let warnings = res.warnings.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+    assert_eq!(warnings.len(), 2, "{:?}", warnings);
+    assert!(warnings.iter().any(|w| w.contains("Missing")));
+    assert!(warnings.iter().any(|w| w.contains("Foo::nothing")));
+    assert!(format_str(source, &FormatConfig::default()).unwrap().warnings.is_empty());
+}
+
+#[test]
+fn ow_check_intra_doc_links_shebang() {
+    let source = r#"#!/usr/bin/env run-cargo-script
+/// Returns [`Some`] [`Missing`].
+fn main() { }
+"#;
+    let res = format_str(source, &FormatConfig {
+        check_intra_doc_links: true,
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(res.rendered, source);
+    assert_eq!(res.warnings.len(), 1);
+    let warning = res.warnings[0].to_string();
+    assert!(warning.contains("Missing") && warning.contains("line = 3"), "{}", warning);
+}
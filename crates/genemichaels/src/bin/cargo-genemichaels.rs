@@ -0,0 +1,75 @@
+//! `cargo genemichaels`: accepts cargo-style package selection arguments and runs
+//! `genemichaels` (installed alongside this) in `cargo metadata` project mode.
+use std::{
+    env::{
+        self,
+        consts::EXE_SUFFIX,
+    },
+    process::{
+        self,
+        Command,
+    },
+};
+
+fn main() {
+    let mut argv = env::args().skip(1).peekable();
+
+    // Cargo passes the subcommand name as the first argument
+    if argv.peek().map(|a| a.as_str()) == Some("genemichaels") {
+        argv.next();
+    }
+    let mut packages = vec![];
+    let mut excludes = vec![];
+    let mut forward = vec!["--cargo-metadata".to_string()];
+    while let Some(arg) = argv.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((f, v)) if f.starts_with("--") => (f.to_string(), Some(v.to_string())),
+            _ => match arg.strip_prefix("-p") {
+                Some(v) if !v.is_empty() => ("-p".to_string(), Some(v.to_string())),
+                _ => (arg.clone(), None),
+            },
+        };
+        let mut value = || match inline_value.clone().or_else(|| argv.next()) {
+            Some(v) => v,
+            None => {
+                eprintln!("Missing value for {}", flag);
+                process::exit(1);
+            },
+        };
+        match flag.as_str() {
+            "-p" | "--package" => packages.push(value()),
+            "--exclude" => excludes.push(value()),
+            "--workspace" | "--all" => forward.push("--workspace".to_string()),
+            "--manifest-path" => {
+                let path = value();
+                forward.push("--manifest-path".to_string());
+                forward.push(path);
+            },
+            _ => forward.push(arg),
+        }
+    }
+
+    // List flags consume arguments up to the next flag, so put them last
+    if !excludes.is_empty() {
+        forward.push("--exclude".to_string());
+        forward.extend(excludes);
+    }
+    if !packages.is_empty() {
+        forward.push("--package".to_string());
+        forward.extend(packages);
+    }
+    let exe = match env::current_exe() {
+        Ok(p) => p.with_file_name(format!("genemichaels{}", EXE_SUFFIX)),
+        Err(e) => {
+            eprintln!("Error locating genemichaels executable: {}", e);
+            process::exit(1);
+        },
+    };
+    match Command::new(&exe).args(forward).status() {
+        Ok(status) => process::exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            eprintln!("Error running {}: {}", exe.to_string_lossy(), e);
+            process::exit(1);
+        },
+    }
+}
@@ -1,13 +1,23 @@
+mod rustfmt;
+
 use {
     aargvark::{
         vark,
         Aargvark,
     },
-    flowcontrol::shed,
     genemichaels_lib::{
+        format_fragment_str,
         format_str,
+        format_str_recover,
+        text_edits,
         FormatConfig,
+        FormatRes,
+        FragmentKind,
+        IndentUnit,
+        NewlineStyle,
+        PreformattedDetection,
     },
+    notify::Watcher,
     loga::{
         ea,
         fatal,
@@ -16,33 +26,59 @@ use {
         Log,
         ResultContext,
     },
-    serde::de::DeserializeOwned,
     std::{
-        collections::HashSet,
+        collections::{
+            HashMap,
+            HashSet,
+        },
         env::current_dir,
         ffi::OsStr,
         fs::{
             self,
             read,
         },
-        io::Read,
+        io::{
+            Read,
+            Write,
+        },
         path::{
             Path,
             PathBuf,
         },
         process,
-        str::pattern::Pattern,
         sync::{
             Arc,
             Mutex,
         },
+        time::{
+            Duration,
+            Instant,
+        },
+    },
+    syn::{
+        ext::IdentExt,
+        punctuated::Punctuated,
+        token::Comma,
+        Attribute,
+        Expr,
+        ExprLit,
+        File,
+        Item,
+        Lit,
+        Meta,
+        MetaNameValue,
     },
-    syn::File,
+    cargo_manifest::StringOrBool,
     threadpool::ThreadPool,
 };
 
 const CARGO_TOML: &str = "Cargo.toml";
 const CONFIG_JSON: &str = ".genemichaels.json";
+const CONFIG_TOML: &str = "genemichaels.toml";
+const METADATA_KEY: &str = "genemichaels";
+const RUSTFMT_TOML: [&str; 2] = [".rustfmt.toml", "rustfmt.toml"];
+const IGNORE_FILE: &str = ".genemichaelsignore";
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
 
 #[derive(Aargvark)]
 enum Logging {
@@ -53,6 +89,29 @@ enum Logging {
     Debug,
 }
 
+#[derive(Aargvark, Clone, Copy)]
+enum Fragment {
+    /// An expression
+    Expr,
+    /// A type
+    Type,
+    /// A pattern
+    Pat,
+    /// Statements, like the inside of a block
+    Stmts,
+    /// Items in an `impl` block
+    ImplItems,
+}
+
+#[derive(Aargvark, Clone, Copy)]
+enum Emit {
+    /// Output the formatted source
+    Text,
+    /// Output a JSON list of edits to apply to the input to format it, with byte
+    /// offset ranges: `[{"range": {"start": 0, "end": 3}, "replacement": "..."}]`
+    EditsJson,
+}
+
 /// A deterministic, simple, rule based Rust source code formatter. Even formats
 /// macros!
 #[derive(Aargvark)]
@@ -66,10 +125,53 @@ struct Args {
     /// Formats the input even if syntactically invalid in most cases, as in some macro
     /// invocations.
     tokens: Option<()>,
-    /// Explicitly specify a config file path. If not specified, will look for
-    /// `.genemichaels.json` next to the `Config.toml` if formatting a project or in
-    /// the current directory otherwise. See the readme for options.
+    /// Format stdin as a fragment of code of this kind rather than a whole file. The
+    /// indentation of the first line is used for all lines.
+    fragment: Option<Fragment>,
+    /// When formatting stdin, the path of the file being formatted. This is used to
+    /// find the config, to skip the file if it's ignored (outputting it unchanged), and
+    /// in error messages.
+    stdin_filepath: Option<PathBuf>,
+    /// What to output when formatting stdin. Defaults to the formatted source.
+    emit: Option<Emit>,
+    /// If the source has syntax errors, format the top level items that can be parsed
+    /// and leave the rest unchanged, rather than failing.
+    recover: Option<()>,
+    /// When formatting a project, find files by following `mod` declarations
+    /// (including `#[path]`) from each target's root file and `build.rs`, rather than
+    /// formatting every `.rs` file in the target directories.
+    follow_modules: Option<()>,
+    /// When formatting a project, don't skip files excluded by `.gitignore`, `.ignore`,
+    /// or `.genemichaelsignore`, or hidden files and directories.
+    no_ignore: Option<()>,
+    /// When formatting a project, use `cargo metadata` to find packages and their
+    /// targets rather than reading manifests directly. Implied by `--manifest-path`,
+    /// `--package`, `--workspace`, and `--exclude`.
+    cargo_metadata: Option<()>,
+    /// The `Cargo.toml` of the project to format, rather than searching from the
+    /// current directory.
+    manifest_path: Option<PathBuf>,
+    /// Format these packages (workspace members or local dependencies). Defaults to
+    /// the package in the current directory, or the workspace's default members.
+    package: Option<Vec<String>>,
+    /// Format all workspace members.
+    workspace: Option<()>,
+    /// With `--workspace`, skip these packages.
+    exclude: Option<Vec<String>>,
+    /// Before overwriting a file, copy the original to the same path with this suffix
+    /// appended (ex: `.orig`).
+    backup: Option<String>,
+    /// After formatting the project, keep running and reformat `.rs` files when they
+    /// change.
+    watch: Option<()>,
+    /// Explicitly specify a config file path (`.genemichaels.json`, `genemichaels.toml`,
+    /// or a `Cargo.toml` with a metadata table). If not specified, will look in the
+    /// current directory and its parents. See the readme for the search order and
+    /// options.
     config: Option<PathBuf>,
+    /// Print the configuration that would be used (for the file if one is passed,
+    /// otherwise for the current directory) and where it was loaded from, then exit.
+    print_config: Option<()>,
     /// Change the log level.
     log: Option<Logging>,
     /// Override how many threads to use for formatting multiple files. Defaults to the
@@ -81,11 +183,8 @@ fn skip(src: &str) -> bool {
     src.lines().take(5).any(|l| l.contains("`nogenemichaels`"))
 }
 
-/// Using existing code that's not quite jsonc, but I think we might as well move
-/// to jsonc at some point.
-fn maybe_load_almost_jsonc<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, loga::Error> {
-    let log = Log::new().fork(ea!(path = path.to_string_lossy()));
-    let log = &log;
+/// Read a config file, or `None` if it doesn't exist.
+fn maybe_read_config(log: &Log, path: &Path) -> Result<Option<String>, loga::Error> {
     let body = match read(path) {
         Ok(b) => b,
         Err(e) => {
@@ -94,29 +193,449 @@ fn maybe_load_almost_jsonc<T: DeserializeOwned>(path: &Path) -> Result<Option<T>
                     return Ok(None);
                 },
                 _ => {
-                    return Err(e.stack_context(log, "Failed to read JSON file"));
+                    return Err(e.stack_context(log, "Failed to read config file"));
                 },
             }
         },
     };
-    return Ok(
-        serde_json::from_str(
-            &String::from_utf8(body).stack_context(log, "Failed to decode JSON file as utf8")?.lines().filter(|l| {
-                if l.trim_start().starts_with("//") {
-                    return false;
+    Ok(Some(String::from_utf8(body).stack_context(log, "Failed to decode config file as utf8")?))
+}
+
+/// Config and descriptions of the files it was loaded from, highest precedence
+/// first.
+struct LoadedConfig {
+    config: FormatConfig,
+    // The keys set in the files, for merging
+    value: serde_json::Value,
+    sources: Vec<String>,
+}
+
+/// Find a key in `value` that's not in `known`, recursing into tables. Returns the
+/// dotted path of the key and of the closest known key at the same level, if any.
+fn unknown_config_key(
+    value: &serde_json::Value,
+    known: &serde_json::Value,
+    prefix: &str,
+) -> Option<(String, Option<String>)> {
+    let (serde_json::Value::Object(fields), serde_json::Value::Object(known)) = (value, known) else {
+        return None;
+    };
+    if known.is_empty() {
+        // A map with arbitrary keys (ex: `macros`)
+        return None;
+    }
+    for (key, field) in fields {
+        let path = format!("{}{}", prefix, key);
+        let Some(known_field) = known.get(key) else {
+            let closest =
+                known
+                    .keys()
+                    .map(|k| (strsim::jaro_winkler(key, k), k))
+                    .filter(|(score, _)| *score > 0.7)
+                    .max_by(|a, b| a.0.total_cmp(&b.0))
+                    .map(|(_, k)| format!("{}{}", prefix, k));
+            return Some((path, closest));
+        };
+        if let Some(found) = unknown_config_key(field, known_field, &format!("{}.", path)) {
+            return Some(found);
+        }
+    }
+    return None;
+}
+
+/// Check for unknown keys (suggesting the closest known key) then deserialize.
+fn parse_config(log: &Log, value: &serde_json::Value) -> Result<FormatConfig, loga::Error> {
+    if !value.is_object() {
+        return Err(log.err("Config must be an object/table"));
+    }
+
+    // Fill in optional sections so their keys are checked too
+    let known = serde_json::to_value(FormatConfig {
+        detect_preformatted: Some(PreformattedDetection::default()),
+        ..Default::default()
+    }).unwrap();
+    match unknown_config_key(value, &known, "") {
+        Some((key, Some(closest))) => {
+            return Err(log.err_with("Unknown config key", ea!(key = key, did_you_mean = closest)));
+        },
+        Some((key, None)) => {
+            return Err(log.err_with("Unknown config key", ea!(key = key)));
+        },
+        None => { },
+    }
+    serde_json::from_value(value.clone()).map_err(|e| log.err_with("Invalid config", ea!(err = e)))
+}
+
+fn loaded_config(log: &Log, value: serde_json::Value, source: String) -> Result<LoadedConfig, loga::Error> {
+    Ok(LoadedConfig {
+        config: parse_config(log, &value)?,
+        value: value,
+        sources: vec![source],
+    })
+}
+
+/// Combine configs, highest precedence first. Tables are merged key by key.
+fn merge_configs(log: &Log, configs: Vec<LoadedConfig>) -> Result<Option<LoadedConfig>, loga::Error> {
+    fn merge(base: &mut serde_json::Value, over: serde_json::Value) {
+        match (base, over) {
+            (serde_json::Value::Object(base), serde_json::Value::Object(over)) => {
+                for (k, v) in over {
+                    match base.get_mut(&k) {
+                        Some(base_v) => merge(base_v, v),
+                        None => {
+                            base.insert(k, v);
+                        },
+                    }
                 }
-                return true;
-            }).collect::<Vec<&str>>().join("\n"),
-        ).stack_context(log, "Failed to parse JSON file")?,
-    );
+            },
+            (base, over) => *base = over,
+        }
+    }
+
+    if configs.len() <= 1 {
+        return Ok(configs.into_iter().next());
+    }
+    let mut value = serde_json::Value::Object(Default::default());
+    let mut sources = vec![];
+    for c in configs.into_iter().rev() {
+        merge(&mut value, c.value);
+        sources.splice(0 .. 0, c.sources);
+    }
+    let log = log.fork(ea!(sources = sources.join(", ")));
+    Ok(Some(LoadedConfig {
+        config: parse_config(&log, &value)?,
+        value: value,
+        sources: sources,
+    }))
 }
 
-fn load_almost_jsonc<T: DeserializeOwned>(path: &Path) -> Result<T, loga::Error> {
-    return Ok(maybe_load_almost_jsonc(path)?.context_with("Path does not exist", ea!(path = path.dbg_str()))?);
+/// JSON with comments (`//` and `/* */`) and trailing commas.
+fn maybe_load_jsonc(path: &Path) -> Result<Option<LoadedConfig>, loga::Error> {
+    let log = Log::new().fork(ea!(path = path.to_string_lossy()));
+    let log = &log;
+    let Some(body) = maybe_read_config(log, path)? else {
+        return Ok(None);
+    };
+    let value =
+        jsonc_parser::parse_to_serde_value(&body, &Default::default())
+            .stack_context(log, "Failed to parse JSONC file")?
+            .unwrap_or(serde_json::Value::Object(Default::default()));
+    Ok(Some(loaded_config(log, value, path.to_string_lossy().to_string())?))
 }
 
-fn process_file_contents(log: &Log, config: &FormatConfig, source: &str) -> Result<String, loga::Error> {
-    let res = format_str(source, config)?;
+fn maybe_load_toml(path: &Path) -> Result<Option<LoadedConfig>, loga::Error> {
+    let log = Log::new().fork(ea!(path = path.to_string_lossy()));
+    let log = &log;
+    let Some(body) = maybe_read_config(log, path)? else {
+        return Ok(None);
+    };
+    let value = toml::from_str::<serde_json::Value>(&body).stack_context(log, "Failed to parse TOML file")?;
+    Ok(Some(loaded_config(log, value, path.to_string_lossy().to_string())?))
+}
+
+/// Config from the `[package.metadata.genemichaels]` table in a `Cargo.toml`, or
+/// `[workspace.metadata.genemichaels]` if there's no package table.
+fn maybe_load_cargo_metadata(path: &Path) -> Result<Option<LoadedConfig>, loga::Error> {
+    let log = Log::new().fork(ea!(path = path.to_string_lossy()));
+    let log = &log;
+    let Some(body) = maybe_read_config(log, path)? else {
+        return Ok(None);
+    };
+    let manifest = body.parse::<toml::Table>().stack_context(log, "Failed to parse Cargo.toml")?;
+    for section in ["package", "workspace"] {
+        let Some(value) =
+            manifest
+                .get(section)
+                .and_then(|s| s.get("metadata"))
+                .and_then(|m| m.get(METADATA_KEY)) else {
+                continue;
+            };
+        let log = log.fork(ea!(section = section));
+        let value =
+            serde_json::to_value(value).stack_context(&log, "Unsupported values in Cargo.toml metadata")?;
+        return Ok(
+            Some(
+                loaded_config(
+                    &log,
+                    value,
+                    format!("{} [{}.metadata.{}]", path.to_string_lossy(), section, METADATA_KEY),
+                )?,
+            ),
+        );
+    }
+    Ok(None)
+}
+
+/// Set the config field corresponding to a rustfmt option if there is one,
+/// otherwise warn.
+fn apply_rustfmt_option(
+    log: &Log,
+    config: &mut FormatConfig,
+    key: &str,
+    value: &toml::Value,
+) -> Result<(), loga::Error> {
+    let log = log.fork(ea!(option = key));
+    let log = &log;
+    let bad_value = || log.err_with("Invalid value for rustfmt option", ea!(value = value));
+    let usize_value = || value.as_integer().and_then(|v| usize::try_from(v).ok()).ok_or_else(bad_value);
+    match key {
+        "max_width" => {
+            config.max_width = usize_value()?;
+        },
+        "tab_spaces" => {
+            config.indent_spaces = usize_value()?;
+        },
+        "hard_tabs" => {
+            config.indent_unit = if value.as_bool().ok_or_else(bad_value)? {
+                IndentUnit::Tabs
+            } else {
+                IndentUnit::Spaces
+            };
+        },
+        "comment_width" => {
+            // Rustfmt's is a column, this is relative to the comment's indentation so there's
+            // no equivalent
+            usize_value()?;
+            log.log(loga::WARN, "Ignoring rustfmt comment_width, set comment_width in the Gene Michaels config instead");
+        },
+        "wrap_comments" => {
+            // Comments can't be left unwrapped, but plain `//` comments can be left alone
+            config.explicit_markdown_comments = !value.as_bool().ok_or_else(bad_value)?;
+        },
+        "newline_style" => {
+            config.newline_style = match value.as_str().ok_or_else(bad_value)? {
+                "Auto" => NewlineStyle::Auto,
+                "Native" => NewlineStyle::Native,
+                "Unix" => NewlineStyle::Lf,
+                "Windows" => NewlineStyle::Crlf,
+                _ => return Err(bad_value()),
+            };
+        },
+        _ => {
+            log.log(loga::WARN, "Ignoring unsupported rustfmt option");
+        },
+    }
+    Ok(())
+}
+
+/// Map the compatible options in a `rustfmt.toml` onto the default config, warning
+/// about the rest.
+fn maybe_load_rustfmt(log: &Log, path: &Path) -> Result<Option<LoadedConfig>, loga::Error> {
+    let log = log.fork(ea!(path = path.to_string_lossy()));
+    let log = &log;
+    let Some(body) = maybe_read_config(log, path)? else {
+        return Ok(None);
+    };
+    let table = body.parse::<toml::Table>().stack_context(log, "Failed to parse rustfmt TOML file")?;
+    let mut config = FormatConfig::default();
+    for (key, value) in &table {
+        apply_rustfmt_option(log, &mut config, key, value)?;
+    }
+    Ok(Some(LoadedConfig {
+        value: serde_json::to_value(&config).unwrap(),
+        config: config,
+        sources: vec![format!("{} (rustfmt)", path.to_string_lossy())],
+    }))
+}
+
+/// Load config from a file, picking the format by file name.
+fn load_config(log: &Log, path: &Path) -> Result<LoadedConfig, loga::Error> {
+    let res = if path.file_name() == Some(OsStr::new(CARGO_TOML)) {
+        maybe_load_cargo_metadata(path)?
+    } else if RUSTFMT_TOML.iter().any(|n| path.file_name() == Some(OsStr::new(n))) {
+        maybe_load_rustfmt(log, path)?
+    } else if path.extension() == Some(OsStr::new("toml")) {
+        maybe_load_toml(path)?
+    } else {
+        maybe_load_jsonc(path)?
+    };
+    res.context_with("Path does not exist or has no config", ea!(path = path.dbg_str()))
+}
+
+/// Find config in a directory, in order of precedence.
+fn maybe_load_config_in(dir: &Path, cargo: bool) -> Result<Option<LoadedConfig>, loga::Error> {
+    if let Some(c) = maybe_load_jsonc(&dir.join(CONFIG_JSON))? {
+        return Ok(Some(c));
+    }
+    if let Some(c) = maybe_load_toml(&dir.join(CONFIG_TOML))? {
+        return Ok(Some(c));
+    }
+    if cargo {
+        if let Some(c) = maybe_load_cargo_metadata(&dir.join(CARGO_TOML))? {
+            return Ok(Some(c));
+        }
+    }
+    Ok(None)
+}
+
+/// Find the config to use for files in `dir`. Gene Michaels config in `dir`, its
+/// parent directories, and the user config directory is merged, with closer files
+/// taking precedence. If there's none, this uses rustfmt config in `dir` or the
+/// closest parent directory.
+fn find_config(log: &Log, dir: &Path) -> Result<Option<LoadedConfig>, loga::Error> {
+    let mut found = vec![];
+    for at in dir.ancestors() {
+        if let Some(c) = maybe_load_config_in(at, true)? {
+            found.push(c);
+        }
+    }
+    if let Some(d) = dirs::config_dir() {
+        if !dir.starts_with(&d) {
+            if let Some(c) = maybe_load_config_in(&d, false)? {
+                found.push(c);
+            }
+        }
+    }
+    if !found.is_empty() {
+        return merge_configs(log, found);
+    }
+    for at in dir.ancestors() {
+        for name in RUSTFMT_TOML {
+            if let Some(c) = maybe_load_rustfmt(log, &at.join(name))? {
+                return Ok(Some(c));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Select packages like cargo does for `-p`, `--workspace`, and `--exclude`.
+fn cargo_packages(
+    log: &Log,
+    manifest_path: &Path,
+    packages: &[String],
+    workspace: bool,
+    exclude: &[String],
+) -> Result<Vec<cargo_metadata::Package>, loga::Error> {
+    if !exclude.is_empty() && !workspace {
+        return Err(log.err("The exclude flag can only be used with the workspace flag"));
+    }
+    let metadata =
+        cargo_metadata::MetadataCommand::new()
+            .manifest_path(manifest_path)
+            .no_deps()
+            .exec()
+            .stack_context(log, "Error running `cargo metadata`")?;
+    if !packages.is_empty() {
+        let mut out = vec![];
+        let mut full_metadata = None;
+        for name in packages {
+            if let Some(p) = metadata.workspace_packages().into_iter().find(|p| &p.name == name) {
+                out.push(p.clone());
+                continue;
+            }
+
+            // Path dependencies aren't in the workspace, only look them up if necessary
+            let full_metadata = match &mut full_metadata {
+                Some(m) => m,
+                None => full_metadata.insert(
+                    cargo_metadata::MetadataCommand::new()
+                        .manifest_path(manifest_path)
+                        .exec()
+                        .stack_context(log, "Error running `cargo metadata` with dependencies")?,
+                ),
+            };
+            let p =
+                full_metadata
+                    .packages
+                    .iter()
+                    .find(|p| &p.name == name && p.source.is_none())
+                    .stack_context_with(
+                        log,
+                        "Package isn't a workspace member or local dependency",
+                        ea!(package = name),
+                    )?;
+            out.push(p.clone());
+        }
+        return Ok(out);
+    }
+    if workspace {
+        return Ok(
+            metadata.workspace_packages().into_iter().filter(|p| !exclude.contains(&p.name)).cloned().collect(),
+        );
+    }
+    if let Some(p) = metadata.root_package() {
+        return Ok(vec![p.clone()]);
+    }
+    Ok(metadata.workspace_default_packages().into_iter().cloned().collect())
+}
+
+/// A directory walker that skips ignored files the same way for all modes.
+fn walker(dir: &Path, no_ignore: bool) -> ignore::WalkBuilder {
+    let mut walk = ignore::WalkBuilder::new(dir);
+    if no_ignore {
+        walk.standard_filters(false);
+    } else {
+        walk.require_git(false).add_custom_ignore_filename(IGNORE_FILE);
+    }
+    walk
+}
+
+/// Whether a file would be skipped by ignore rules when formatting its project. This
+/// walks from the package root to the file with the same settings as directory
+/// runs, so all the same ignore sources apply. If the file doesn't exist, only the
+/// directories leading to it are checked.
+fn is_ignored(path: &Path, no_ignore: bool) -> bool {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return false;
+    };
+    let Ok(dir) = dir.canonicalize() else {
+        return false;
+    };
+    let file = dir.join(name);
+    let target = if file.exists() {
+        file
+    } else {
+        dir.clone()
+    };
+    let root = dir.ancestors().find(|d| d.join(CARGO_TOML).exists()).unwrap_or(&dir);
+    let filter_target = target.clone();
+    !walker(root, no_ignore)
+        .filter_entry(move |e| filter_target.starts_with(e.path()))
+        .build()
+        .filter_map(|e| e.ok())
+        .any(|e| e.path() == target)
+}
+
+/// Replace a file's contents without leaving it truncated if interrupted: write a
+/// temporary file next to it, sync, then rename over the original. Symlinks are
+/// resolved so the target is replaced rather than the link, and the original
+/// permissions are kept.
+fn write_atomic(path: &Path, data: &[u8], backup: Option<&str>) -> Result<(), loga::Error> {
+    let path = path.canonicalize().context("Error resolving file path")?;
+    let dir = path.parent().context("File has no parent directory")?;
+    let name = path.file_name().context("File path has no file name")?.to_string_lossy().to_string();
+    let permissions = fs::metadata(&path).context("Error reading file metadata")?.permissions();
+    if let Some(suffix) = backup {
+        fs::copy(&path, dir.join(format!("{}{}", name, suffix))).context("Error writing backup file")?;
+    }
+    let temp_path = dir.join(format!(".{}.genemichaels-{}", name, process::id()));
+    let res = || -> Result<(), loga::Error> {
+        let mut temp =
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&temp_path)
+                .context("Error creating temporary file")?;
+        temp.write_all(data).context("Error writing temporary file")?;
+        temp.set_permissions(permissions).context("Error setting temporary file permissions")?;
+        temp.sync_all().context("Error syncing temporary file")?;
+        fs::rename(&temp_path, &path).context("Error replacing file with temporary file")?;
+        return Ok(());
+    }();
+    if res.is_err() {
+        _ = fs::remove_file(&temp_path);
+    }
+    res?;
+
+    // Make the rename durable; not possible on all platforms so ignore errors
+    if let Ok(d) = fs::File::open(dir) {
+        _ = d.sync_all();
+    }
+    return Ok(());
+}
+
+fn check_lost_comments(log: &Log, res: &FormatRes) -> Result<(), loga::Error> {
     if !res.lost_comments.is_empty() {
         return Err(
             log.err_with(
@@ -125,8 +644,58 @@ fn process_file_contents(log: &Log, config: &FormatConfig, source: &str) -> Resu
             ),
         );
     }
+    Ok(())
+}
+
+fn process_fragment_contents(
+    log: &Log,
+    config: &FormatConfig,
+    fragment: Fragment,
+    source: &str,
+) -> Result<String, loga::Error> {
+    let indent = source.chars().skip_while(|c| *c == '\n').take_while(|c| *c == ' ' || *c == '\t');
+    let indent = match config.indent_unit {
+        IndentUnit::Spaces => indent.filter(|c| *c == ' ').count() / config.indent_spaces.max(1),
+        IndentUnit::Tabs => indent.filter(|c| *c == '\t').count(),
+    };
+    let res = format_fragment_str(source, match fragment {
+        Fragment::Expr => FragmentKind::Expr,
+        Fragment::Type => FragmentKind::Type,
+        Fragment::Pat => FragmentKind::Pat,
+        Fragment::Stmts => FragmentKind::Stmts,
+        Fragment::ImplItems => FragmentKind::ImplItems,
+    }, indent, config)?;
+    for warning in &res.warnings {
+        log.log_err(loga::WARN, warning.clone());
+    }
+    check_lost_comments(log, &res)?;
+    let mut out = res.rendered;
+    if !source.ends_with('\n') {
+        out.pop();
+    }
+    Ok(out)
+}
+
+fn process_file_contents(
+    log: &Log,
+    config: &FormatConfig,
+    recover: bool,
+    source: &str,
+) -> Result<String, loga::Error> {
+    let res = if recover {
+        format_str_recover(source, config)?
+    } else {
+        format_str(source, config)?
+    };
+    for warning in &res.warnings {
+        log.log_err(loga::WARN, warning.clone());
+    }
+    check_lost_comments(log, &res)?;
     match syn::parse_str::<File>(&res.rendered) {
         Ok(_) => { },
+        Err(_) if res.unparsed_items > 0 => {
+            // Unparsable parts were left as-is
+        },
         Err(e) => {
             return Err(
                 log.err_with(
@@ -154,6 +723,10 @@ fn process_file_contents(log: &Log, config: &FormatConfig, source: &str) -> Resu
 }
 
 fn main() {
+    let mut argv = std::env::args();
+    if argv.next().as_deref().and_then(|a| Path::new(a).file_stem()) == Some(OsStr::new("rustfmt")) {
+        rustfmt::main(argv);
+    }
     let args = vark::<Args>();
     let log = Log::new_root(match args.log {
         Some(Logging::Silent) => loga::WARN,
@@ -162,38 +735,63 @@ fn main() {
     });
     let log = &log;
     let res = || -> Result<(), loga::Error> {
-        let config = shed!{
-            'found_config _;
-            // Try exact location if specified
-            if let Some(path) = args.config {
-                break 'found_config load_almost_jsonc(&path)?;
-            }
-            // Search current and all parent directories
-            {
+        let stdin_filepath = match &args.stdin_filepath {
+            Some(p) => Some(
+                current_dir().context("Error determining current directory, resolving stdin file path")?.join(p),
+            ),
+            None => None,
+        };
+        if args.print_config.is_some() && args.files.len() > 1 {
+            return Err(log.err("The print-config flag takes at most one file"));
+        }
+        let config_for = match (&args.print_config, args.files.first()) {
+            (Some(_), Some(p)) => Some(
+                current_dir().context("Error determining current directory, resolving file path")?.join(p),
+            ),
+            _ => stdin_filepath.clone(),
+        };
+        let loaded_config = match &args.config {
+            Some(path) => Some(load_config(log, path)?),
+            None => {
                 let cwd = current_dir().context("Error determining current directory, during search for config")?;
-                let mut at = cwd.as_path();
-                loop {
-                    if let Some(c) = maybe_load_almost_jsonc(&at.join(CONFIG_JSON))? {
-                        break 'found_config c;
+                find_config(log, match &config_for {
+                    Some(p) => p.parent().unwrap_or(&cwd),
+                    None => cwd.as_path(),
+                })?
+            },
+        };
+        if args.print_config.is_some() {
+            match &loaded_config {
+                Some(c) => {
+                    for source in &c.sources {
+                        println!("// Config from {}", source);
                     }
-                    let Some(next_at) = at.parent() else {
-                        break;
-                    };
-                    at = next_at;
-                }
-            }
-            // Try global config directory
-            if let Some(d) = dirs::config_dir() {
-                if let Some(c) = maybe_load_almost_jsonc(&d.join(CONFIG_JSON))? {
-                    break 'found_config c;
-                }
+                },
+                None => println!("// No config file found, using defaults"),
             }
-            // No config, use default settings
-            break Default::default();
-        };
+            let config = loaded_config.map(|c| c.config).unwrap_or_default();
+            println!("{}", serde_json::to_string_pretty(&config).unwrap());
+            return Ok(());
+        }
+        let config = loaded_config.map(|c| c.config).unwrap_or_default();
         if args.stdin.is_none() && args.tokens.is_some() {
             return Err(log.err("If you use tokens flag stdin must be used"));
         }
+        if args.stdin.is_none() && args.fragment.is_some() {
+            return Err(log.err("If you use fragment flag stdin must be used"));
+        }
+        if args.stdin.is_none() && args.stdin_filepath.is_some() {
+            return Err(log.err("If you use stdin-filepath flag stdin must be used"));
+        }
+        if args.stdin.is_none() && args.emit.is_some() {
+            return Err(log.err("If you use emit flag stdin must be used"));
+        }
+        if args.tokens.is_some() && args.fragment.is_some() {
+            return Err(log.err("The tokens and fragment flags can't be used together"));
+        }
+        if args.watch.is_some() && (args.stdin.is_some() || !args.files.is_empty()) {
+            return Err(log.err("The watch flag can only be used when formatting a project"));
+        }
         if args.stdin.is_some() {
             if !args.files.is_empty() {
                 return Err(
@@ -203,13 +801,22 @@ fn main() {
                     ),
                 )
             }
+            let log = &match &stdin_filepath {
+                Some(p) => log.fork(ea!(file = p.to_string_lossy())),
+                None => log.clone(),
+            };
             || -> Result<(), loga::Error> {
                 let mut source = Vec::new();
                 std::io::stdin().read_to_end(&mut source)?;
-                let source = String::from_utf8(source)?;
-                if skip(&source) {
-                    print!("{}", source);
-                    return Ok(());
+                let original = String::from_utf8(source)?;
+                let source = original.clone();
+                let out = if skip(&source) {
+                    source
+                } else if stdin_filepath.as_ref().is_some_and(|p| is_ignored(p, args.no_ignore.is_some())) {
+                    log.log_with(loga::INFO, "Skipping due to ignore rules", ea!());
+                    source
+                } else if let Some(fragment) = args.fragment {
+                    process_fragment_contents(log, &config, fragment, &source)?
                 } else {
                     let mut preserving_trailing_newline = false;
                     let mut indent = String::new();
@@ -222,7 +829,7 @@ fn main() {
                     } else {
                         source
                     };
-                    let out = process_file_contents(log, &config, &source)?;
+                    let out = process_file_contents(log, &config, args.recover.is_some(), &source)?;
                     let out = if args.tokens.is_some() {
                         let start = out.find('{').unwrap() + 1;
                         let end = out.rfind('}').unwrap();
@@ -239,25 +846,39 @@ fn main() {
                     } else {
                         out
                     };
-                    print!("{}{}", out, if preserving_trailing_newline {
+                    format!("{}{}", out, if preserving_trailing_newline {
                         "\n"
                     } else {
                         ""
-                    });
-                    return Ok(());
+                    })
+                };
+                match args.emit {
+                    None | Some(Emit::Text) => {
+                        print!("{}", out);
+                    },
+                    Some(Emit::EditsJson) => {
+                        println!(
+                            "{}",
+                            serde_json::to_string(&text_edits(&original, &out)).context("Error serializing edits")?
+                        );
+                    },
                 }
+                return Ok(());
             }().stack_context(log, "Error formatting stdin")?;
         } else if !args.files.is_empty() {
-            let mut pool = FormatPool::new(log, args.thread_count, config);
+            let mut pool = FormatPool::new(log, args.thread_count, config, args.recover.is_some(), args.backup.clone());
             for file in args.files {
                 pool.process_file(file);
             }
             pool.join()?;
         } else {
-            let mut project_cargo_toml = None;
+            let mut project_cargo_toml = args.manifest_path.clone();
             let c_dir = current_dir()?;
             let mut at: Option<&Path> = Some(&c_dir);
             while let Some(d) = at.take() {
+                if project_cargo_toml.is_some() {
+                    break;
+                }
                 let cargo_toml_path = d.join(CARGO_TOML);
                 if cargo_toml_path.exists() {
                     project_cargo_toml = Some(cargo_toml_path);
@@ -270,10 +891,38 @@ fn main() {
                     log.err("Couldn't find a Cargo.toml manifest in any directory up to filesystem root; aborting"),
                 );
             };
+            let manifest_path = c_dir.join(manifest_path);
 
             struct DirSearch {
                 seen: HashSet<PathBuf>,
                 pool: FormatPool,
+                follow_modules: bool,
+                // Module files found with `--follow-modules`, and whether each is `mod_rs` (see
+                // `process_module_file`)
+                modules: HashMap<PathBuf, bool>,
+                no_ignore: bool,
+                // Directories to watch with `--watch`, and whether to watch recursively
+                watch: Vec<(PathBuf, bool)>,
+            }
+
+            fn walk_dir(dir: &Path, no_ignore: bool) -> Vec<PathBuf> {
+                let mut out = vec![];
+                for f in walker(dir, no_ignore).build() {
+                    match f {
+                        Ok(file) => {
+                            let file_path = file.path().to_path_buf();
+                            if file_path.extension() != Some(OsStr::new("rs")) {
+                                continue;
+                            }
+                            out.push(file_path);
+                        },
+                        Err(e) => {
+                            eprintln!("Error while scanning dir {}: {}", dir.to_string_lossy(), e);
+                            continue;
+                        },
+                    }
+                }
+                out
             }
 
             fn process_dir(search: &mut DirSearch, dir: PathBuf) {
@@ -283,20 +932,172 @@ fn main() {
                 if !dir.exists() {
                     return;
                 }
-                for f in walkdir::WalkDir::new(&dir) {
-                    match f {
-                        Ok(file) => {
-                            let file_path = file.path().to_path_buf();
-                            if !search.seen.insert(file_path.clone()) ||
-                                file_path.extension() != Some(OsStr::new("rs")) {
-                                continue;
+                search.watch.push((dir.clone(), true));
+                for file_path in walk_dir(&dir, search.no_ignore) {
+                    if !search.seen.insert(file_path.clone()) {
+                        continue;
+                    }
+                    search.pool.process_file(file_path);
+                }
+            }
+
+            /// Paths from `#[path = "..."]` and `#[cfg_attr(..., path = "...")]`
+            fn mod_paths(attrs: &[Attribute]) -> Vec<String> {
+                let mut out = vec![];
+                for attr in attrs {
+                    if attr.path().is_ident("path") {
+                        if let Meta::NameValue(
+                            MetaNameValue { value: Expr::Lit(ExprLit { lit: Lit::Str(s), .. }), .. },
+                        ) = &attr.meta {
+                            out.push(s.value());
+                        }
+                    } else if attr.path().is_ident("cfg_attr") {
+                        let Ok(metas) = attr.parse_args_with(Punctuated::<Meta, Comma>::parse_terminated) else {
+                            continue;
+                        };
+                        for meta in metas.into_iter().skip(1) {
+                            if let Meta::NameValue(
+                                MetaNameValue { path, value: Expr::Lit(ExprLit { lit: Lit::Str(s), .. }), .. },
+                            ) = meta {
+                                if path.is_ident("path") {
+                                    out.push(s.value());
+                                }
+                            }
+                        }
+                    }
+                }
+                out
+            }
+
+            /// Find files for `mod` declarations. `file_dir` is where `#[path]`s are relative
+            /// to, `child_dir` is where `mod x;` looks for `x.rs`.
+            fn follow_mods(search: &mut DirSearch, file_dir: &Path, child_dir: &Path, items: &[Item]) {
+                for item in items {
+                    let Item::Mod(m) = item else {
+                        continue;
+                    };
+                    let paths = mod_paths(&m.attrs);
+                    let name = m.ident.unraw().to_string();
+                    match &m.content {
+                        Some((_, items)) => {
+                            let dir = child_dir.join(paths.first().unwrap_or(&name));
+                            follow_mods(search, &dir, &dir, items);
+                        },
+                        None => {
+                            if paths.is_empty() {
+                                let path = child_dir.join(format!("{}.rs", name));
+                                if path.exists() {
+                                    process_module_file(search, path, false);
+                                } else {
+                                    process_module_file(search, child_dir.join(&name).join("mod.rs"), true);
+                                }
+                            } else {
+                                for path in paths {
+                                    // Files loaded with `#[path]` look for submodules next to themselves, like
+                                    // `mod.rs`
+                                    process_module_file(search, file_dir.join(path), true);
+                                }
                             }
-                            search.pool.process_file(file_path);
                         },
+                    }
+                }
+            }
+
+            /// Format a crate root or module file and all the module files it declares.
+            /// `mod_rs` is true for files whose submodules are in the same directory (crate
+            /// roots and `mod.rs`).
+            fn process_module_file(search: &mut DirSearch, path: PathBuf, mod_rs: bool) {
+                // Canonicalize to dedupe paths reached via `..` in `#[path]`
+                let Ok(path) = path.canonicalize() else {
+                    // Watch for the file being created
+                    if let Some(dir) = path.ancestors().skip(1).find(|d| d.is_dir()) {
+                        watch_module_dir(search, dir);
+                    }
+                    return;
+                };
+                if !search.seen.insert(path.clone()) {
+                    return;
+                }
+                watch_module_dir(search, path.parent().unwrap());
+                search.modules.insert(path.clone(), mod_rs);
+
+                // Read the modules before queueing the file, since formatting rewrites it
+                let items = module_items(search, &path);
+                search.pool.process_file(path.clone());
+                if let Some(items) = items {
+                    follow_module_file_mods(search, &path, mod_rs, &items);
+                }
+            }
+
+            fn watch_module_dir(search: &mut DirSearch, dir: &Path) {
+                if !search.watch.iter().any(|(d, _)| d == dir) {
+                    search.watch.push((dir.to_path_buf(), false));
+                }
+            }
+
+            fn module_items(search: &DirSearch, path: &Path) -> Option<Vec<Item>> {
+                match fs::read_to_string(path).context("Error reading file").and_then(|source| {
+                    syn::parse_file(&source).context("Error parsing file")
+                }) {
+                    Ok(f) => Some(f.items),
+                    Err(e) => {
+                        search
+                            .pool
+                            .log
+                            .log_err(
+                                loga::WARN,
+                                e.context_with(
+                                    "Couldn't read module file, not following its modules",
+                                    ea!(path = path.to_string_lossy()),
+                                ),
+                            );
+                        None
+                    },
+                }
+            }
+
+            /// Process the module files declared in a module file that's already been
+            /// processed.
+            fn follow_module_file_mods(search: &mut DirSearch, path: &Path, mod_rs: bool, items: &[Item]) {
+                let dir = path.parent().unwrap().to_path_buf();
+                let child_dir = if mod_rs {
+                    dir.clone()
+                } else {
+                    dir.join(path.file_stem().unwrap())
+                };
+                follow_mods(search, &dir, &child_dir, items);
+            }
+
+            fn process_workspace_members(search: &mut DirSearch, manifest_dir: &Path, manifest: &cargo_manifest::Manifest) {
+                for member in manifest.workspace.iter().flat_map(|ws| ws.members.iter()) {
+                    let member = manifest_dir.join(member);
+                    if member == manifest_dir {
+                        continue;
+                    }
+
+                    // NOTE: glob::glob takes a &str instead of a path. May cause problems in the
+                    // future on systems with non-UTF-8 paths, such as Linux.
+                    if member.to_str().is_none() {
+                        eprintln!(
+                            "Crate or workspace member path is not UTF-8, some members may fail to format."
+                        );
+                    }
+                    let entries = match glob::glob(&member.to_string_lossy().into_owned()) {
+                        Ok(entries) => entries,
                         Err(e) => {
-                            eprintln!("Error while scanning dir {}: {}", dir.to_string_lossy(), e);
+                            eprintln!("Failed to parse glob pattern {}: {}", member.to_string_lossy(), e);
                             continue;
                         },
+                    };
+                    for entry in entries {
+                        match entry {
+                            Ok(path) => process_manifest(search, path.join(CARGO_TOML)),
+                            Err(e) => eprintln!(
+                                "Error while reading dir {}: {}",
+                                e.path().to_string_lossy(),
+                                e
+                            ),
+                        }
                     }
                 }
             }
@@ -304,63 +1105,48 @@ fn main() {
             fn process_manifest(search: &mut DirSearch, manifest_path: PathBuf) {
                 let manifest_dir = manifest_path.parent().unwrap();
                 match cargo_manifest::Manifest::from_path(&manifest_path) {
+                    Ok(manifest) if search.follow_modules => {
+                        let mut roots = vec![];
+                        roots.extend(manifest.lib.iter().filter_map(|t| t.path.clone()));
+                        for targets in [&manifest.bin, &manifest.bench, &manifest.test, &manifest.example] {
+                            roots.extend(targets.iter().filter_map(|t| t.path.clone()));
+                        }
+                        if let Some(StringOrBool::String(build)) = manifest.package.as_ref().and_then(|p| p.build.as_ref()) {
+                            roots.push(build.clone());
+                        }
+                        for root in roots {
+                            process_module_file(search, manifest_dir.join(root), true);
+                        }
+                        process_workspace_members(search, manifest_dir, &manifest);
+                        return;
+                    },
                     Ok(manifest) => {
-                        for bin in manifest.bin.into_iter() {
-                            if let Some(bin_path) = bin.path {
+                        for bin in &manifest.bin {
+                            if let Some(bin_path) = &bin.path {
                                 process_dir(search, manifest_dir.join(bin_path).parent().unwrap().to_owned());
                             }
                         }
-                        if let Some(lib) = manifest.lib {
-                            if let Some(lib_path) = lib.path {
+                        if let Some(lib) = &manifest.lib {
+                            if let Some(lib_path) = &lib.path {
                                 process_dir(search, manifest_dir.join(lib_path).parent().unwrap().to_owned());
                             }
                         }
-                        for bench in manifest.bench.into_iter() {
-                            if let Some(bench_path) = bench.path {
+                        for bench in &manifest.bench {
+                            if let Some(bench_path) = &bench.path {
                                 process_dir(search, manifest_dir.join(bench_path).parent().unwrap().to_owned());
                             }
                         }
-                        for test in manifest.test.into_iter() {
-                            if let Some(test_path) = test.path {
+                        for test in &manifest.test {
+                            if let Some(test_path) = &test.path {
                                 process_dir(search, manifest_dir.join(test_path).parent().unwrap().to_owned());
                             }
                         }
-                        for example in manifest.example.into_iter() {
-                            if let Some(example_path) = example.path {
+                        for example in &manifest.example {
+                            if let Some(example_path) = &example.path {
                                 process_dir(search, manifest_dir.join(example_path).parent().unwrap().to_owned());
                             }
                         }
-                        for member in manifest.workspace.map(|ws| ws.members).into_iter().flatten() {
-                            let member = manifest_dir.join(member);
-                            if member == manifest_dir {
-                                continue;
-                            }
-
-                            // NOTE: glob::glob takes a &str instead of a path. May cause problems in the
-                            // future on systems with non-UTF-8 paths, such as Linux.
-                            if member.to_str().is_none() {
-                                eprintln!(
-                                    "Crate or workspace member path is not UTF-8, some members may fail to format."
-                                );
-                            }
-                            let entries = match glob::glob(&member.to_string_lossy().into_owned()) {
-                                Ok(entries) => entries,
-                                Err(e) => {
-                                    eprintln!("Failed to parse glob pattern {}: {}", member.to_string_lossy(), e);
-                                    continue;
-                                },
-                            };
-                            for entry in entries {
-                                match entry {
-                                    Ok(path) => process_manifest(search, path.join(CARGO_TOML)),
-                                    Err(e) => eprintln!(
-                                        "Error while reading dir {}: {}",
-                                        e.path().to_string_lossy(),
-                                        e
-                                    ),
-                                }
-                            }
-                        }
+                        process_workspace_members(search, manifest_dir, &manifest);
                     },
                     Err(e) => {
                         search
@@ -382,12 +1168,182 @@ fn main() {
                 process_dir(search, manifest_dir.join("src"));
             }
 
+            fn process_cargo_package(search: &mut DirSearch, package: &cargo_metadata::Package) {
+                let package_dir = package.manifest_path.parent().unwrap().as_std_path();
+                for target in &package.targets {
+                    let root = target.src_path.as_std_path();
+                    let root_dir = root.parent().unwrap();
+                    if search.follow_modules || target.is_kind(cargo_metadata::TargetKind::CustomBuild) ||
+                        root_dir == package_dir {
+                        // Don't walk the whole package for targets in the package root
+                        process_module_file(search, root.to_path_buf(), true);
+                    } else {
+                        process_dir(search, root_dir.to_path_buf());
+                    }
+                }
+            }
+
             let mut search = DirSearch {
                 seen: HashSet::new(),
-                pool: FormatPool::new(log, args.thread_count, config),
+                pool: FormatPool::new(log, args.thread_count, config, args.recover.is_some(), args.backup.clone()),
+                follow_modules: args.follow_modules.is_some(),
+                modules: HashMap::new(),
+                no_ignore: args.no_ignore.is_some(),
+                watch: vec![],
             };
-            process_manifest(&mut search, manifest_path);
-            search.pool.join()?;
+
+            // Config for files in each package dir, when not using one config for everything
+            let mut package_configs = vec![];
+            if args.cargo_metadata.is_some() || args.manifest_path.is_some() || args.package.is_some() ||
+                args.workspace.is_some() ||
+                args.exclude.is_some() {
+                let packages =
+                    cargo_packages(
+                        log,
+                        &manifest_path,
+                        args.package.as_deref().unwrap_or_default(),
+                        args.workspace.is_some(),
+                        args.exclude.as_deref().unwrap_or_default(),
+                    )?;
+                for package in &packages {
+                    if args.config.is_none() {
+                        // Search from the package rather than the current directory (ex: with
+                        // `--manifest-path`)
+                        let package_dir = package.manifest_path.parent().unwrap().as_std_path();
+                        search.pool.config = find_config(log, package_dir)?.map(|c| c.config).unwrap_or_default();
+                        package_configs.push((package_dir.to_path_buf(), search.pool.config.clone()));
+                    }
+                    process_cargo_package(&mut search, package);
+                }
+            } else {
+                process_manifest(&mut search, manifest_path);
+            }
+            if args.watch.is_none() {
+                search.pool.join()?;
+                return Ok(());
+            }
+            if let Err(e) = search.pool.join() {
+                log.log_err(loga::WARN, e);
+            }
+
+            // Reformat files as they change
+            let (events_tx, events_rx) = std::sync::mpsc::channel();
+            let mut watcher = notify::recommended_watcher(events_tx).context("Error starting file watcher")?;
+            let mut watched = 0;
+            for (dir, recursive) in &search.watch {
+                watcher
+                    .watch(dir, if *recursive {
+                        notify::RecursiveMode::Recursive
+                    } else {
+                        notify::RecursiveMode::NonRecursive
+                    })
+                    .context_with("Error watching directory", ea!(dir = dir.to_string_lossy()))?;
+                watched += 1;
+            }
+            let package_config = |path: &Path| {
+                package_configs
+                    .iter()
+                    .filter(|(dir, _)| path.starts_with(dir))
+                    .max_by_key(|(dir, _)| dir.components().count())
+                    .map(|(_, config)| config)
+            };
+            log.log(loga::INFO, "Watching for changes");
+            while let Ok(event) = events_rx.recv() {
+                // Wait for changes to settle, editors often save in several steps. Files being
+                // read (ex: by an editor) don't delay formatting.
+                let mut changed = HashSet::new();
+                let mut settled = Instant::now() + WATCH_DEBOUNCE;
+                let mut event = Some(event);
+                while let Some(e) = event.take() {
+                    match e {
+                        Ok(e) => {
+                            if !matches!(e.kind, notify::EventKind::Access(_) | notify::EventKind::Remove(_)) {
+                                changed.extend(e.paths);
+                                settled = Instant::now() + WATCH_DEBOUNCE;
+                            }
+                        },
+                        Err(e) => {
+                            log.log_with(loga::WARN, "Error watching files", ea!(err = e));
+                        },
+                    }
+                    event = events_rx.recv_timeout(settled.saturating_duration_since(Instant::now())).ok();
+                }
+                let new_dirs = changed.iter().any(|p| !search.seen.contains(p) && p.is_dir());
+                let mut changed_files = vec![];
+                for path in changed {
+                    if path.extension() != Some(OsStr::new("rs")) || !path.is_file() {
+                        continue;
+                    }
+
+                    // Skip changes from our own writes
+                    if let Some(written) = search.pool.written.lock().unwrap().remove(&path) {
+                        if fs::read(&path).map(|b| b == written).unwrap_or(false) {
+                            continue;
+                        }
+                    }
+                    changed_files.push(path);
+                }
+                let new_files =
+                    changed_files.iter().filter(|p| !search.seen.contains(*p)).cloned().collect::<HashSet<_>>();
+
+                // Pick up added `mod` declarations, or new files and directories for earlier
+                // declarations
+                if search.follow_modules {
+                    let modules =
+                        search
+                            .modules
+                            .iter()
+                            .filter(|(p, _)| new_dirs || !new_files.is_empty() || changed_files.contains(p))
+                            .map(|(p, mod_rs)| (p.clone(), *mod_rs))
+                            .collect::<Vec<_>>();
+                    for (path, mod_rs) in modules {
+                        if let Some(config) = package_config(&path) {
+                            search.pool.config = config.clone();
+                        }
+                        if let Some(items) = module_items(&search, &path) {
+                            follow_module_file_mods(&mut search, &path, mod_rs, &items);
+                        }
+                    }
+                    for (dir, recursive) in &search.watch[watched..] {
+                        if let Err(e) = watcher.watch(dir, if *recursive {
+                            notify::RecursiveMode::Recursive
+                        } else {
+                            notify::RecursiveMode::NonRecursive
+                        }) {
+                            log.log_with(
+                                loga::WARN,
+                                "Error watching directory",
+                                ea!(dir = dir.to_string_lossy(), err = e),
+                            );
+                        }
+                    }
+                    watched = search.watch.len();
+                }
+                for path in changed_files {
+                    // New files must be somewhere the initial search would have found them. With
+                    // `--follow-modules` any new module files were processed above.
+                    if new_files.contains(&path) {
+                        if search.follow_modules {
+                            continue;
+                        }
+                        let Some((root, _)) =
+                            search.watch.iter().find(|(root, recursive)| *recursive && path.starts_with(root)) else {
+                                continue;
+                            };
+                        if !walk_dir(root, search.no_ignore).contains(&path) {
+                            continue;
+                        }
+                        search.seen.insert(path.clone());
+                    }
+                    if let Some(config) = package_config(&path) {
+                        search.pool.config = config.clone();
+                    }
+                    search.pool.process_file(path);
+                }
+                if let Err(e) = search.pool.join() {
+                    log.log_err(loga::WARN, e);
+                }
+            }
         }
         return Ok(());
     }();
@@ -406,15 +1362,28 @@ fn main() {
 struct FormatPool {
     log: Log,
     config: FormatConfig,
+    recover: bool,
+    backup: Option<String>,
     pool: ThreadPool,
+    // Data last written to each file, so watching can ignore the resulting events
+    written: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
     errors: Arc<Mutex<Vec<loga::Error>>>,
 }
 
 impl FormatPool {
-    fn new(log: &Log, thread_count: Option<usize>, config: FormatConfig) -> FormatPool {
+    fn new(
+        log: &Log,
+        thread_count: Option<usize>,
+        config: FormatConfig,
+        recover: bool,
+        backup: Option<String>,
+    ) -> FormatPool {
         return FormatPool {
             log: log.clone(),
             config: config,
+            recover: recover,
+            backup: backup,
+            written: Arc::new(Mutex::new(HashMap::new())),
             pool: {
                 let mut p = threadpool::Builder::new();
                 if let Some(t) = thread_count {
@@ -430,6 +1399,9 @@ impl FormatPool {
         let log = self.log.fork(ea!(file = file.to_string_lossy()));
         log.log_with(loga::INFO, "Processing file", ea!());
         let config = self.config.clone();
+        let recover = self.recover;
+        let backup = self.backup.clone();
+        let written = self.written.clone();
         let errors = self.errors.clone();
         self.pool.execute(move || {
             let log = &log;
@@ -439,10 +1411,12 @@ impl FormatPool {
                     log.log_with(loga::INFO, "Skipping due to skip comment", ea!());
                     return Ok(());
                 }
-                let processed = process_file_contents(log, &config, &source).context("Error doing formatting")?;
+                let processed = process_file_contents(log, &config, recover, &source).context("Error doing formatting")?;
                 if source != processed {
                     log.log_with(loga::INFO, "Writing newly formatted file", ea!());
-                    fs::write(&file, processed.as_bytes()).context("Error writing formatted code back")?;
+                    write_atomic(&file, processed.as_bytes(), backup.as_deref())
+                        .context("Error writing formatted code back")?;
+                    written.lock().unwrap().insert(file.clone(), processed.into_bytes());
                 }
                 return Ok(());
             }().stack_context(log, "Error formatting file");
@@ -460,9 +1434,9 @@ impl FormatPool {
         if self.pool.panic_count() > 0 {
             return Err(self.log.err("Panic(s) occurred during formatting."));
         }
-        let errors = self.errors.lock().unwrap();
+        let errors = std::mem::take(&mut *self.errors.lock().unwrap());
         if !errors.is_empty() {
-            return Err(loga::agg_err("Errors encountered during formatting.", errors.clone()));
+            return Err(loga::agg_err("Errors encountered during formatting.", errors));
         }
         Ok(())
     }
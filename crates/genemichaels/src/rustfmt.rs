@@ -0,0 +1,293 @@
+//! A command line compatible with the commonly used parts of rustfmt's, used when
+//! the binary is invoked as `rustfmt` (ex: via a symlink) so it can be dropped in
+//! wherever rustfmt is expected.
+use {
+    super::{
+        apply_rustfmt_option,
+        find_config,
+        load_config,
+        maybe_load_config_in,
+        maybe_load_rustfmt,
+        process_file_contents,
+        skip,
+        write_atomic,
+        RUSTFMT_TOML,
+    },
+    genemichaels_lib::FormatConfig,
+    loga::{
+        ea,
+        fatal,
+        Log,
+        ResultContext,
+    },
+    similar::{
+        ChangeTag,
+        TextDiff,
+    },
+    std::{
+        env::current_dir,
+        fs,
+        io::Read,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process,
+    },
+};
+
+const USAGE: &str = "usage: rustfmt [options] <file>...
+
+Formats files in place, or stdin to stdout if no files are given, using
+genemichaels. Supported rustfmt options:
+
+    --check                 Don't write files, print a diff and exit 1 if any
+                            file isn't formatted
+    --emit files|stdout     Where to write formatted files
+    --backup                Copy modified files to <file>.bk before writing
+    -l, --files-with-diff   Print the names of files that were (or would be)
+                            changed
+    --config-path <path>    Config file, or a directory to look for one in
+    --config <k=v,...>      Override rustfmt options
+    --edition <edition>     Ignored
+    -q, --quiet             Don't print file names with --emit stdout
+    -v, --verbose           Ignored
+    -V, --version           Print the version
+    -h, --help              Print this message
+";
+
+struct RustfmtArgs {
+    files: Vec<PathBuf>,
+    check: bool,
+    emit_stdout: bool,
+    files_with_diff: bool,
+    backup: bool,
+    quiet: bool,
+    config_path: Option<PathBuf>,
+    config: Vec<(String, String)>,
+}
+
+fn parse_args(log: &Log, mut argv: impl Iterator<Item = String>) -> Result<RustfmtArgs, loga::Error> {
+    let mut out = RustfmtArgs {
+        files: vec![],
+        check: false,
+        emit_stdout: false,
+        files_with_diff: false,
+        backup: false,
+        quiet: false,
+        config_path: None,
+        config: vec![],
+    };
+    while let Some(arg) = argv.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((f, v)) if f.starts_with("--") => (f.to_string(), Some(v.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || -> Result<String, loga::Error> {
+            match inline_value.clone().or_else(|| argv.next()) {
+                Some(v) => Ok(v),
+                None => Err(log.err_with("Missing value for rustfmt option", ea!(option = flag))),
+            }
+        };
+        match flag.as_str() {
+            "--check" => out.check = true,
+            "--emit" => {
+                let emit = value()?;
+                match emit.as_str() {
+                    "files" => out.emit_stdout = false,
+                    "stdout" => out.emit_stdout = true,
+                    _ => return Err(log.err_with("Unsupported rustfmt emit mode", ea!(emit = emit))),
+                }
+            },
+            "-l" | "--files-with-diff" => out.files_with_diff = true,
+            "--backup" => out.backup = true,
+            "-q" | "--quiet" => out.quiet = true,
+            "--config-path" => out.config_path = Some(PathBuf::from(value()?)),
+            "--config" => {
+                for kv in value()?.split(',') {
+                    let Some((k, v)) = kv.split_once('=') else {
+                        return Err(log.err_with("Invalid rustfmt config override, expected `key=value`", ea!(value = kv)));
+                    };
+                    out.config.push((k.trim().to_string(), v.trim().to_string()));
+                }
+            },
+            "--edition" | "--style-edition" | "--color" => {
+                value()?;
+            },
+            "-v" | "--verbose" | "--unstable-features" | "--skip-children" | "--error-on-unformatted" => { },
+            "-V" | "--version" => {
+                println!("rustfmt {} (genemichaels)", env!("CARGO_PKG_VERSION"));
+                process::exit(0);
+            },
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                process::exit(0);
+            },
+            "--" => {
+                out.files.extend(argv.by_ref().map(PathBuf::from));
+            },
+            _ if flag.starts_with('-') && flag != "-" => {
+                return Err(log.err_with("Unsupported rustfmt option", ea!(option = flag)));
+            },
+            _ => out.files.push(PathBuf::from(arg)),
+        }
+    }
+    Ok(out)
+}
+
+/// Print a diff in rustfmt's `--check` format.
+fn print_diff(name: &str, original: &str, formatted: &str) {
+    let diff = TextDiff::from_lines(original, formatted);
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        let line = hunk.ops().first().map(|op| op.old_range().start).unwrap_or(0) + 1;
+        println!("Diff in {}:{}:", name, line);
+        for change in hunk.iter_changes() {
+            let sign = match change.tag() {
+                ChangeTag::Delete => "-",
+                ChangeTag::Insert => "+",
+                ChangeTag::Equal => " ",
+            };
+            print!("{}{}", sign, change.value());
+            if change.missing_newline() {
+                println!();
+            }
+        }
+    }
+}
+
+struct Rustfmt {
+    log: Log,
+    args: RustfmtArgs,
+    // Whether any input wasn't formatted
+    diffs: bool,
+}
+
+impl Rustfmt {
+    /// Config for a file in `dir`, per `--config-path` and `--config` or searching as
+    /// in normal mode.
+    fn config(&self, dir: &Path) -> Result<FormatConfig, loga::Error> {
+        let log = &self.log;
+        let loaded = match &self.args.config_path {
+            Some(p) if p.is_dir() => {
+                let mut loaded = maybe_load_config_in(p, true)?;
+                for name in RUSTFMT_TOML {
+                    if loaded.is_none() {
+                        loaded = maybe_load_rustfmt(log, &p.join(name))?;
+                    }
+                }
+                Some(loaded.context_with("No config found in config path", ea!(path = p.to_string_lossy()))?)
+            },
+            Some(p) => Some(load_config(log, p)?),
+            None => find_config(log, dir)?,
+        };
+        let mut config = loaded.map(|c| c.config).unwrap_or_default();
+        for (k, v) in &self.args.config {
+            let value = match toml::from_str::<toml::Table>(&format!("v = {}", v)) {
+                Ok(mut t) => t.remove("v").unwrap(),
+                Err(_) => toml::Value::String(v.clone()),
+            };
+            apply_rustfmt_option(log, &mut config, k, &value)?;
+        }
+        Ok(config)
+    }
+
+    /// Handle the formatted version of an input, returning whether to write it back.
+    fn output(&mut self, name: &str, source: &str, formatted: &str) -> bool {
+        let changed = source != formatted;
+        if self.args.check {
+            if changed {
+                self.diffs = true;
+                if self.args.files_with_diff {
+                    println!("{}", name);
+                } else {
+                    print_diff(name, source, formatted);
+                }
+            }
+            return false;
+        }
+        if changed && self.args.files_with_diff {
+            println!("{}", name);
+        }
+        if self.args.emit_stdout {
+            if !self.args.quiet {
+                println!("{}:\n", name);
+            }
+            print!("{}", formatted);
+            return false;
+        }
+        changed
+    }
+
+    fn format_stdin(&mut self) -> Result<(), loga::Error> {
+        let log = self.log.clone();
+        let mut source = String::new();
+        std::io::stdin().read_to_string(&mut source).context("Error reading stdin")?;
+        let config = self.config(&current_dir().context("Error determining current directory")?)?;
+        let formatted = if skip(&source) {
+            source.clone()
+        } else {
+            process_file_contents(&log, &config, false, &source)?
+        };
+        if self.args.check {
+            self.output("<stdin>", &source, &formatted);
+        } else {
+            print!("{}", formatted);
+        }
+        Ok(())
+    }
+
+    fn format_file(&mut self, path: &Path) -> Result<(), loga::Error> {
+        let log = self.log.fork(ea!(file = path.to_string_lossy()));
+        let source = fs::read_to_string(path).stack_context(&log, "Failed to read source file")?;
+        let formatted = if skip(&source) {
+            // Still output as-is with `--emit stdout`
+            source.clone()
+        } else {
+            let abs_path = current_dir().context("Error determining current directory")?.join(path);
+            let config = self.config(abs_path.parent().unwrap())?;
+            process_file_contents(&log, &config, false, &source)?
+        };
+        if self.output(&path.to_string_lossy(), &source, &formatted) {
+            write_atomic(path, formatted.as_bytes(), if self.args.backup {
+                Some(".bk")
+            } else {
+                None
+            }).stack_context(&log, "Error writing formatted code back")?;
+        }
+        Ok(())
+    }
+}
+
+/// Run in rustfmt mode, exiting with rustfmt's exit codes: 0 on success, 1 on
+/// errors or if `--check` found unformatted code.
+pub(crate) fn main(argv: impl Iterator<Item = String>) -> ! {
+    let log = Log::new_root(loga::WARN);
+    let log = &log;
+    let res = || -> Result<bool, loga::Error> {
+        let args = parse_args(log, argv)?;
+        let mut rustfmt = Rustfmt {
+            log: log.clone(),
+            args: args,
+            diffs: false,
+        };
+        if rustfmt.args.files.is_empty() || rustfmt.args.files == [PathBuf::from("-")] {
+            rustfmt.format_stdin()?;
+            return Ok(rustfmt.diffs);
+        }
+        let mut errors = vec![];
+        for file in rustfmt.args.files.clone() {
+            if let Err(e) = rustfmt.format_file(&file) {
+                errors.push(e);
+            }
+        }
+        if !errors.is_empty() {
+            return Err(loga::agg_err("Errors encountered during formatting.", errors));
+        }
+        Ok(rustfmt.diffs)
+    }();
+    match res {
+        Ok(false) => process::exit(0),
+        Ok(true) => process::exit(1),
+        Err(e) => fatal(e),
+    }
+}
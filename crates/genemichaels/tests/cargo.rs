@@ -0,0 +1,87 @@
+use {
+    common::{
+        command,
+        output,
+        UNFORMATTED,
+    },
+    std::{
+        fs,
+        path::Path,
+        process::Output,
+    },
+    tempfile::TempDir,
+};
+
+mod common;
+
+/// A workspace with members `a`, `b` and `c` in `ws`, each with an unformatted
+/// `lib.rs`, and an empty `cwd` dir to run from.
+fn fixture() -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    let ws = dir.path().join("ws");
+    fs::create_dir_all(dir.path().join("cwd")).unwrap();
+    fs::create_dir_all(&ws).unwrap();
+    fs::write(ws.join("Cargo.toml"), "[workspace]\nmembers = [\"a\", \"b\", \"c\"]\nresolver = \"2\"\n").unwrap();
+    for name in ["a", "b", "c"] {
+        let src = ws.join(name).join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(
+            ws.join(name).join("Cargo.toml"),
+            format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n", name),
+        ).unwrap();
+        fs::write(src.join("lib.rs"), UNFORMATTED).unwrap();
+    }
+    dir
+}
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    let output =
+        output(
+            command(env!("CARGO_BIN_EXE_cargo-genemichaels"), dir)
+                .arg("genemichaels")
+                .args(args)
+                .current_dir(dir.join("cwd")),
+            None,
+        );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+/// The indentation of the formatted `lib.rs` in each member, or `None` if it
+/// wasn't formatted.
+fn indents(dir: &Path) -> Vec<Option<usize>> {
+    ["a", "b", "c"].iter().map(|name| {
+        let text = fs::read_to_string(dir.join("ws").join(name).join("src").join("lib.rs")).unwrap();
+        if text == UNFORMATTED {
+            return None;
+        }
+        let line = text.lines().nth(1).unwrap();
+        Some(line.len() - line.trim_start().len())
+    }).collect()
+}
+
+#[test]
+fn select_packages() {
+    let dir = fixture();
+    run(dir.path(), &["--manifest-path", "../ws/Cargo.toml", "-p", "a", "--package=c"]);
+    assert_eq!(indents(dir.path()), vec![Some(4), None, Some(4)]);
+}
+
+#[test]
+fn select_workspace_exclude() {
+    let dir = fixture();
+    run(dir.path(), &["--manifest-path=../ws/Cargo.toml", "--workspace", "--exclude", "b"]);
+    assert_eq!(indents(dir.path()), vec![Some(4), None, Some(4)]);
+}
+
+#[test]
+fn config_from_package_dir() {
+    let dir = fixture();
+
+    // Not used, the config is found from each package instead of the current dir
+    fs::write(dir.path().join("cwd").join(".genemichaels.json"), "{\"indent_spaces\": 6}").unwrap();
+    fs::write(dir.path().join("ws").join(".genemichaels.json"), "{\"indent_spaces\": 2}").unwrap();
+    fs::write(dir.path().join("ws").join("b").join(".genemichaels.json"), "{\"indent_spaces\": 3}").unwrap();
+    run(dir.path(), &["--manifest-path", "../ws/Cargo.toml", "--all"]);
+    assert_eq!(indents(dir.path()), vec![Some(2), Some(3), Some(2)]);
+}
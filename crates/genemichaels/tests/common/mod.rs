@@ -0,0 +1,55 @@
+// Each test crate only uses some of these
+#![allow(dead_code)]
+
+use {
+    std::{
+        ffi::OsStr,
+        io::Write,
+        path::Path,
+        process::{
+            Command,
+            Output,
+            Stdio,
+        },
+    },
+};
+
+pub const UNFORMATTED: &str = "fn f(){\n    let   x = 1;\n}\n";
+pub const FORMATTED: &str = "fn f() {\n    let x = 1;\n}\n";
+
+/// Run `exe` in `dir`, with `dir` as the home and user config directory to keep
+/// any global config out of the search.
+pub fn command(exe: impl AsRef<OsStr>, dir: &Path) -> Command {
+    let mut c = Command::new(exe);
+    c.current_dir(dir).env("HOME", dir).env("XDG_CONFIG_HOME", dir.join(".config"));
+    c
+}
+
+pub fn genemichaels(dir: &Path) -> Command {
+    command(env!("CARGO_BIN_EXE_genemichaels"), dir)
+}
+
+/// Run the command to completion, writing `stdin` to it and capturing stdout and
+/// stderr.
+pub fn output(command: &mut Command, stdin: Option<&str>) -> Output {
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+    let mut pipe = child.stdin.take().unwrap();
+    pipe.write_all(stdin.unwrap_or("").as_bytes()).unwrap();
+    drop(pipe);
+    child.wait_with_output().unwrap()
+}
+
+pub fn run(dir: &Path, args: &[&str]) -> Output {
+    output(genemichaels(dir).args(args), None)
+}
+
+/// Like `run`, but checks that it succeeded.
+pub fn run_ok(dir: &Path, args: &[&str]) -> Output {
+    let output = run(dir, args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
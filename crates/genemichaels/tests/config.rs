@@ -0,0 +1,239 @@
+use {
+    common::run,
+    std::{
+        fs,
+        path::Path,
+    },
+};
+
+mod common;
+
+/// The config printed by `--print-config`, and the files it says it came from.
+fn print_config(dir: &Path) -> (Vec<String>, serde_json::Value) {
+    let output = run(dir, &["--print-config"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut sources = vec![];
+    let mut config = String::new();
+    for line in stdout.lines() {
+        if let Some(source) = line.strip_prefix("// Config from ") {
+            sources.push(source.to_string());
+        } else if !line.starts_with("//") {
+            config.push_str(line);
+        }
+    }
+    (sources, serde_json::from_str(&config).unwrap())
+}
+
+/// Stderr from `--print-config`, which should fail.
+fn print_config_err(dir: &Path) -> String {
+    let output = run(dir, &["--print-config"]);
+    assert!(!output.status.success());
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn rustfmt_toml_options_mapped() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("rustfmt.toml"),
+        "max_width = 80\ntab_spaces = 2\nhard_tabs = true\nwrap_comments = false\nnewline_style = \"Windows\"\n",
+    ).unwrap();
+    let (sources, config) = print_config(dir.path());
+    assert_eq!(sources.len(), 1);
+    assert!(sources[0].ends_with("rustfmt.toml (rustfmt)"), "{:?}", sources);
+    assert_eq!(config["max_width"], 80);
+    assert_eq!(config["indent_spaces"], 2);
+    assert_eq!(config["indent_unit"], "tabs");
+    assert_eq!(config["explicit_markdown_comments"], true);
+    assert_eq!(config["newline_style"], "crlf");
+}
+
+#[test]
+fn rustfmt_toml_unsupported_options_ignored() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join(".rustfmt.toml"),
+        "normalize_comments = false\nedition = \"2021\"\ncomment_width = 60\n",
+    ).unwrap();
+    let output = run(dir.path(), &["--print-config"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Ignoring unsupported rustfmt option"), "{}", stderr);
+    assert!(stderr.contains("normalize_comments"), "{}", stderr);
+    assert!(stderr.contains("Ignoring rustfmt comment_width"), "{}", stderr);
+    let (_, config) = print_config(dir.path());
+    assert_eq!(config["keep_inline_block_comments"], false);
+    assert_eq!(config["comment_width"], 80);
+}
+
+#[test]
+fn rustfmt_toml_invalid_value() {
+    let dir = tempfile::tempdir().unwrap();
+    for body in ["max_width = \"wide\"\n", "max_width = -1\n", "tab_spaces = -4\n"] {
+        fs::write(dir.path().join("rustfmt.toml"), body).unwrap();
+        let output = run(dir.path(), &["--print-config"]);
+        assert!(!output.status.success(), "{}", body);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid value for rustfmt option"));
+    }
+}
+
+#[test]
+fn genemichaels_config_preferred_over_rustfmt() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("rustfmt.toml"), "max_width = 80\n").unwrap();
+    fs::write(dir.path().join(".genemichaels.json"), "{\"max_width\": 90}").unwrap();
+    let (sources, config) = print_config(dir.path());
+    assert_eq!(sources.len(), 1);
+    assert!(sources[0].ends_with(".genemichaels.json"), "{:?}", sources);
+    assert_eq!(config["max_width"], 90);
+}
+
+#[test]
+fn unknown_key_suggestion() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(".genemichaels.json"), "{\"max_widht\": 90}").unwrap();
+    let stderr = print_config_err(dir.path());
+    assert!(stderr.contains("Unknown config key"), "{}", stderr);
+    assert!(stderr.contains("key = max_widht"), "{}", stderr);
+    assert!(stderr.contains("did_you_mean = max_width"), "{}", stderr);
+    assert!(stderr.contains(".genemichaels.json"), "{}", stderr);
+}
+
+#[test]
+fn unknown_nested_key_suggestion() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("genemichaels.toml"), "[markdown]\nbulet = \"-\"\n").unwrap();
+    let stderr = print_config_err(dir.path());
+    assert!(stderr.contains("key = markdown.bulet"), "{}", stderr);
+    assert!(stderr.contains("did_you_mean = markdown.bullet"), "{}", stderr);
+    assert!(stderr.contains("genemichaels.toml"), "{}", stderr);
+    fs::write(dir.path().join("genemichaels.toml"), "[detect_preformatted]\nmin_line = 2\n").unwrap();
+    let stderr = print_config_err(dir.path());
+    assert!(stderr.contains("key = detect_preformatted.min_line"), "{}", stderr);
+    assert!(stderr.contains("did_you_mean = detect_preformatted.min_lines"), "{}", stderr);
+
+    // Macro names are arbitrary
+    fs::write(dir.path().join("genemichaels.toml"), "[macros]\nhtml = \"verbatim\"\n").unwrap();
+    let (_, config) = print_config(dir.path());
+    assert_eq!(config["macros"]["html"], "verbatim");
+}
+
+#[test]
+fn unknown_key_without_suggestion() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(".genemichaels.json"), "{\"zzz\": 1}").unwrap();
+    let stderr = print_config_err(dir.path());
+    assert!(stderr.contains("key = zzz"), "{}", stderr);
+    assert!(!stderr.contains("did_you_mean"), "{}", stderr);
+}
+
+#[test]
+fn merged_from_parents() {
+    let dir = tempfile::tempdir().unwrap();
+    let sub = dir.path().join("sub");
+    fs::create_dir(&sub).unwrap();
+    fs::write(
+        dir.path().join(".genemichaels.json"),
+        "{\"max_width\": 90, \"indent_spaces\": 2, \"markdown\": {\"bullet\": \"-\"}}",
+    ).unwrap();
+    fs::write(sub.join("genemichaels.toml"), "max_width = 100\n[markdown]\nstrong = \"__\"\n").unwrap();
+    let (sources, config) = print_config(&sub);
+    assert_eq!(sources.len(), 2, "{:?}", sources);
+    assert!(sources[0].ends_with("genemichaels.toml"), "{:?}", sources);
+    assert!(sources[1].ends_with(".genemichaels.json"), "{:?}", sources);
+    assert_eq!(config["max_width"], 100);
+    assert_eq!(config["indent_spaces"], 2);
+    assert_eq!(config["markdown"]["bullet"], "-");
+    assert_eq!(config["markdown"]["strong"], "__");
+}
+
+#[test]
+fn jsonc_comments_and_trailing_commas() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join(".genemichaels.json"),
+        "{\n    // Line comment\n    \"max_width\": 90, /* block\n    comment */\n    \"macros\": {\"html\": \"verbatim\",},\n}\n",
+    ).unwrap();
+    let (sources, config) = print_config(dir.path());
+    assert!(sources[0].ends_with(".genemichaels.json"), "{:?}", sources);
+    assert_eq!(config["max_width"], 90);
+    assert_eq!(config["macros"]["html"], "verbatim");
+}
+
+#[test]
+fn toml_config() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("genemichaels.toml"), "max_width = 90\n\n[markdown]\nbullet = \"-\"\n").unwrap();
+    let (sources, config) = print_config(dir.path());
+    assert!(sources[0].ends_with("genemichaels.toml"), "{:?}", sources);
+    assert_eq!(config["max_width"], 90);
+    assert_eq!(config["markdown"]["bullet"], "-");
+}
+
+#[test]
+fn cargo_metadata_config() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("Cargo.toml"),
+        "[package]\nname = \"p\"\nversion = \"0.1.0\"\n\n[package.metadata.genemichaels]\nmax_width = 90\n\n[workspace.metadata.genemichaels]\nmax_width = 70\n",
+    ).unwrap();
+    let (sources, config) = print_config(dir.path());
+    assert!(sources[0].ends_with("Cargo.toml [package.metadata.genemichaels]"), "{:?}", sources);
+    assert_eq!(config["max_width"], 90);
+
+    // Workspace table is used if there's no package table
+    fs::write(
+        dir.path().join("Cargo.toml"),
+        "[workspace]\n\n[workspace.metadata.genemichaels]\nmax_width = 70\n",
+    ).unwrap();
+    let (sources, config) = print_config(dir.path());
+    assert!(sources[0].ends_with("Cargo.toml [workspace.metadata.genemichaels]"), "{:?}", sources);
+    assert_eq!(config["max_width"], 70);
+
+    // No table, no config
+    fs::write(dir.path().join("Cargo.toml"), "[package]\nname = \"p\"\nversion = \"0.1.0\"\n").unwrap();
+    let (sources, _) = print_config(dir.path());
+    assert!(sources.is_empty(), "{:?}", sources);
+}
+
+#[test]
+fn config_precedence() {
+    let dir = tempfile::tempdir().unwrap();
+    let project = dir.path();
+    let global = project.join(".config");
+    fs::create_dir_all(&global).unwrap();
+    fs::write(
+        global.join("genemichaels.toml"),
+        "max_width = 50\nindent_spaces = 5\nkeep_max_blank_lines = 5\n",
+    ).unwrap();
+    fs::write(project.join("rustfmt.toml"), "max_width = 60\n").unwrap();
+    fs::write(
+        project.join("Cargo.toml"),
+        "[package]\nname = \"p\"\nversion = \"0.1.0\"\n\n[package.metadata.genemichaels]\nmax_width = 70\nindent_spaces = 3\n",
+    ).unwrap();
+    let (sources, config) = print_config(project);
+    assert_eq!(sources.len(), 2, "{:?}", sources);
+    assert!(sources[0].ends_with("Cargo.toml [package.metadata.genemichaels]"), "{:?}", sources);
+    assert!(sources[1].ends_with("genemichaels.toml"), "{:?}", sources);
+    assert_eq!(config["max_width"], 70);
+    assert_eq!(config["indent_spaces"], 3);
+    assert_eq!(config["keep_max_blank_lines"], 5);
+
+    // In one directory only the first file found is used
+    fs::write(project.join("genemichaels.toml"), "max_width = 80\n").unwrap();
+    let (_, config) = print_config(project);
+    assert_eq!(config["max_width"], 80);
+    assert_eq!(config["indent_spaces"], 5);
+    fs::write(project.join(".genemichaels.json"), "{\"max_width\": 90}").unwrap();
+    let (sources, config) = print_config(project);
+    assert!(sources[0].ends_with(".genemichaels.json"), "{:?}", sources);
+    assert_eq!(config["max_width"], 90);
+
+    // An explicit path overrides everything
+    let output = run(project, &["--print-config", "--config", "genemichaels.toml"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("\"max_width\": 80"), "{}", stdout);
+    assert!(stdout.contains("\"indent_spaces\": 4"), "{}", stdout);
+}
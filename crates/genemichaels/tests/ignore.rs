@@ -0,0 +1,64 @@
+use {
+    common::{
+        genemichaels,
+        output,
+        stdout,
+        UNFORMATTED,
+    },
+    std::{
+        fs,
+        path::Path,
+    },
+};
+
+mod common;
+
+/// A package where some files are ignored via `.gitignore`, `.git/info/exclude`,
+/// or not thanks to a closer `.gitignore`.
+fn fixture(dir: &Path) {
+    let src = dir.join("src");
+    fs::create_dir_all(src.join("gen")).unwrap();
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::create_dir_all(dir.join(".git").join("info")).unwrap();
+    fs::write(dir.join("Cargo.toml"), "[package]\nname = \"p\"\nversion = \"0.1.0\"\nedition = \"2021\"\n").unwrap();
+    fs::write(dir.join(".gitignore"), "gen/\n*.gen.rs\n").unwrap();
+    fs::write(dir.join(".git").join("info").join("exclude"), "excluded.rs\n").unwrap();
+    fs::write(src.join("sub").join(".gitignore"), "!keep.gen.rs\n").unwrap();
+    for f in ["lib.rs", "excluded.rs", "gen/g.rs", "sub/keep.gen.rs", "sub/drop.gen.rs"] {
+        fs::write(src.join(f), UNFORMATTED).unwrap();
+    }
+}
+
+/// Whether formatting stdin for the file changes it.
+fn stdin_formats(dir: &Path, file: &str, extra: &[&str]) -> bool {
+    let output =
+        output(genemichaels(dir).args(["--stdin", "--stdin-filepath", file]).args(extra), Some(UNFORMATTED));
+    assert!(output.status.success());
+    stdout(&output) != UNFORMATTED
+}
+
+#[test]
+fn stdin_filepath_matches_directory_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    fixture(dir);
+    let files = ["src/lib.rs", "src/excluded.rs", "src/gen/g.rs", "src/sub/keep.gen.rs", "src/sub/drop.gen.rs"];
+    let want = [true, false, false, true, false];
+    let stdin = files.iter().map(|f| stdin_formats(dir, f, &[])).collect::<Vec<_>>();
+    assert_eq!(stdin, want);
+    assert!(genemichaels(dir).status().unwrap().success());
+    let walked =
+        files.iter().map(|f| fs::read_to_string(dir.join(f)).unwrap() != UNFORMATTED).collect::<Vec<_>>();
+    assert_eq!(walked, want);
+}
+
+#[test]
+fn stdin_filepath_new_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    fixture(dir);
+    assert!(stdin_formats(dir, "src/new.rs", &[]));
+    assert!(!stdin_formats(dir, "src/gen/new.rs", &[]));
+    assert!(stdin_formats(dir, "src/gen/new.rs", &["--no-ignore"]));
+    assert!(stdin_formats(dir, "src/excluded.rs", &["--no-ignore"]));
+}
@@ -0,0 +1,71 @@
+use {
+    common::{
+        run_ok,
+        UNFORMATTED,
+    },
+    std::{
+        fs,
+        path::Path,
+    },
+};
+
+mod common;
+
+/// Module files reached from `lib.rs` and `build.rs`, and the `mod` declarations
+/// in each.
+const MODULES: &[(&str, &str)] = &[
+    (
+        "src/lib.rs",
+        "mod a;\nmod c;\n#[path = \"other/e_file.rs\"]\nmod e;\n#[cfg_attr(unix, path = \"g_unix.rs\")]\nmod g;\nmod inline {\n    mod h;\n    #[path = \"i_file.rs\"]\n    mod i;\n}\n",
+    ),
+    ("src/a.rs", "mod b;\n"),
+    ("src/a/b.rs", ""),
+    ("src/c/mod.rs", "mod d;\n"),
+    ("src/c/d.rs", ""),
+    ("src/other/e_file.rs", "mod f;\n"),
+    ("src/other/f.rs", ""),
+    ("src/g_unix.rs", ""),
+    ("src/inline/h.rs", ""),
+    ("src/inline/i_file.rs", ""),
+    ("build.rs", ""),
+];
+
+/// Files that aren't part of the crate.
+const STRAY: &[&str] = &["src/stray.rs", "src/a/stray.rs", "src/other/stray.rs"];
+
+fn fixture(dir: &Path) {
+    fs::write(dir.join("Cargo.toml"), "[package]\nname = \"p\"\nversion = \"0.1.0\"\nedition = \"2021\"\n").unwrap();
+    for (path, mods) in MODULES {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, format!("{}{}", mods, UNFORMATTED)).unwrap();
+    }
+    for path in STRAY {
+        fs::write(dir.join(path), UNFORMATTED).unwrap();
+    }
+}
+
+/// Whether each file was changed from the fixture.
+fn formatted(dir: &Path, files: &[&str]) -> Vec<bool> {
+    files.iter().map(|f| !fs::read_to_string(dir.join(f)).unwrap().ends_with(UNFORMATTED)).collect()
+}
+
+#[test]
+fn follow_modules() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    fixture(dir);
+    run_ok(dir, &["--follow-modules"]);
+    let modules = MODULES.iter().map(|(f, _)| *f).collect::<Vec<_>>();
+    assert_eq!(formatted(dir, &modules), vec![true; modules.len()], "{:?}", modules);
+    assert_eq!(formatted(dir, STRAY), vec![false; STRAY.len()]);
+}
+
+#[test]
+fn without_follow_modules() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    fixture(dir);
+    run_ok(dir, &[]);
+    assert_eq!(formatted(dir, STRAY), vec![true; STRAY.len()]);
+}
@@ -0,0 +1,145 @@
+use {
+    common::{
+        command,
+        output,
+        stdout,
+        FORMATTED,
+        UNFORMATTED,
+    },
+    std::{
+        env::consts::EXE_SUFFIX,
+        fs,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Output,
+    },
+    tempfile::TempDir,
+};
+
+mod common;
+
+/// A temp dir with a copy of the binary named `rustfmt`, which should act like
+/// rustfmt.
+fn setup() -> (TempDir, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let exe = dir.path().join(format!("rustfmt{}", EXE_SUFFIX));
+    fs::copy(env!("CARGO_BIN_EXE_genemichaels"), &exe).unwrap();
+    (dir, exe)
+}
+
+fn run(exe: &Path, dir: &Path, args: &[&str], stdin: Option<&str>) -> Output {
+    output(command(exe, dir).args(args), stdin)
+}
+
+#[test]
+fn dispatch_on_argv0() {
+    let (dir, exe) = setup();
+    let output = run(&exe, dir.path(), &["--version"], None);
+    assert!(output.status.success());
+    assert!(stdout(&output).starts_with("rustfmt "), "{}", stdout(&output));
+
+    // Not under the rustfmt name, rustfmt options aren't accepted
+    let output = run(Path::new(env!("CARGO_BIN_EXE_genemichaels")), dir.path(), &["--version"], None);
+    assert!(!stdout(&output).starts_with("rustfmt "));
+}
+
+#[test]
+fn format_files_in_place() {
+    let (dir, exe) = setup();
+    fs::write(dir.path().join("a.rs"), UNFORMATTED).unwrap();
+    let output = run(&exe, dir.path(), &["--edition", "2021", "a.rs"], None);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(fs::read_to_string(dir.path().join("a.rs")).unwrap(), FORMATTED);
+    assert!(!dir.path().join("a.rs.bk").exists());
+}
+
+#[test]
+fn backup() {
+    let (dir, exe) = setup();
+    fs::write(dir.path().join("a.rs"), UNFORMATTED).unwrap();
+    let output = run(&exe, dir.path(), &["--backup", "a.rs"], None);
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(dir.path().join("a.rs")).unwrap(), FORMATTED);
+    assert_eq!(fs::read_to_string(dir.path().join("a.rs.bk")).unwrap(), UNFORMATTED);
+}
+
+#[test]
+fn stdin_to_stdout() {
+    let (dir, exe) = setup();
+    let output = run(&exe, dir.path(), &[], Some(UNFORMATTED));
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), FORMATTED);
+    let output = run(&exe, dir.path(), &["--emit", "stdout", "-"], Some(UNFORMATTED));
+    assert_eq!(stdout(&output), FORMATTED);
+}
+
+#[test]
+fn emit_stdout_file_headers() {
+    let (dir, exe) = setup();
+    fs::write(dir.path().join("a.rs"), UNFORMATTED).unwrap();
+    fs::write(dir.path().join("b.rs"), FORMATTED).unwrap();
+    let output = run(&exe, dir.path(), &["--emit=stdout", "a.rs", "b.rs"], None);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), format!("a.rs:\n\n{}b.rs:\n\n{}", FORMATTED, FORMATTED));
+    assert_eq!(fs::read_to_string(dir.path().join("a.rs")).unwrap(), UNFORMATTED);
+    let output = run(&exe, dir.path(), &["-q", "--emit", "stdout", "a.rs"], None);
+    assert_eq!(stdout(&output), FORMATTED);
+}
+
+#[test]
+fn check_diff_and_exit_code() {
+    let (dir, exe) = setup();
+    fs::write(dir.path().join("a.rs"), UNFORMATTED).unwrap();
+    fs::write(dir.path().join("b.rs"), FORMATTED).unwrap();
+    let output = run(&exe, dir.path(), &["--check", "a.rs", "b.rs"], None);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "Diff in a.rs:1:\n-fn f(){\n-    let   x = 1;\n+fn f() {\n+    let x = 1;\n }\n");
+    assert_eq!(fs::read_to_string(dir.path().join("a.rs")).unwrap(), UNFORMATTED);
+    let output = run(&exe, dir.path(), &["--check", "-l", "a.rs", "b.rs"], None);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "a.rs\n");
+    let output = run(&exe, dir.path(), &["--check", "b.rs"], None);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+    let output = run(&exe, dir.path(), &["--check"], Some(UNFORMATTED));
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).starts_with("Diff in <stdin>:1:\n"));
+}
+
+#[test]
+fn config_overrides() {
+    let (dir, exe) = setup();
+    fs::write(dir.path().join("rustfmt.toml"), "tab_spaces = 2\n").unwrap();
+    fs::write(dir.path().join("a.rs"), UNFORMATTED).unwrap();
+    let output = run(&exe, dir.path(), &["a.rs"], None);
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(dir.path().join("a.rs")).unwrap(), "fn f() {\n  let x = 1;\n}\n");
+    let output = run(&exe, dir.path(), &["--config", "tab_spaces=3,hard_tabs=false", "a.rs"], None);
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(dir.path().join("a.rs")).unwrap(), "fn f() {\n   let x = 1;\n}\n");
+    fs::create_dir(dir.path().join("conf")).unwrap();
+    fs::write(dir.path().join("conf").join(".genemichaels.json"), "{\"indent_spaces\": 5}").unwrap();
+    let output = run(&exe, dir.path(), &["--config-path", "conf", "a.rs"], None);
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(dir.path().join("a.rs")).unwrap(), "fn f() {\n     let x = 1;\n}\n");
+}
+
+#[test]
+fn errors_exit_1() {
+    let (dir, exe) = setup();
+    fs::write(dir.path().join("bad.rs"), "fn main( {\n").unwrap();
+    for args in [
+        &["--unknown-option"][..],
+        &["--emit", "json"],
+        &["--config", "max_width"],
+        &["--config-path"],
+        &["missing.rs"],
+        &["bad.rs"],
+    ] {
+        let output = run(&exe, dir.path(), args, None);
+        assert_eq!(output.status.code(), Some(1), "{:?}", args);
+    }
+}
@@ -0,0 +1,113 @@
+use {
+    common::{
+        genemichaels,
+        UNFORMATTED,
+    },
+    std::{
+        fs,
+        io::{
+            BufRead,
+            BufReader,
+        },
+        path::Path,
+        process::{
+            Child,
+            Stdio,
+        },
+        thread::sleep,
+        time::{
+            Duration,
+            Instant,
+        },
+    },
+};
+
+mod common;
+
+/// Kills the watcher when the test ends, including on panic.
+struct Watch(Child);
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        _ = self.0.kill();
+        _ = self.0.wait();
+    }
+}
+
+/// Start `--watch` in `dir` and wait for the initial formatting to finish.
+fn watch(dir: &Path, args: &[&str]) -> Watch {
+    let mut child = genemichaels(dir).arg("--watch").args(args).stderr(Stdio::piped()).spawn().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let watch = Watch(child);
+    let mut lines = BufReader::new(stderr).lines();
+    loop {
+        let line = lines.next().expect("Watcher exited before watching").unwrap();
+        if line.contains("Watching for changes") {
+            break;
+        }
+    }
+
+    // Keep reading so the watcher doesn't block on a full pipe
+    std::thread::spawn(move || for _ in lines { });
+    watch
+}
+
+fn is_formatted(path: &Path) -> bool {
+    return fs::read_to_string(path).map(|t| !t.ends_with(UNFORMATTED)).unwrap_or(false);
+}
+
+/// Wait for the file to be formatted, or time out.
+fn wait_formatted(path: &Path) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        if is_formatted(path) {
+            return true;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    return false;
+}
+
+fn fixture(dir: &Path) {
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(dir.join("Cargo.toml"), "[package]\nname = \"p\"\nversion = \"0.1.0\"\nedition = \"2021\"\n").unwrap();
+    fs::write(dir.join("src/lib.rs"), UNFORMATTED).unwrap();
+}
+
+#[test]
+fn watch_reformats_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    fixture(dir);
+    let _watch = watch(dir, &[]);
+    assert!(is_formatted(&dir.join("src/lib.rs")));
+    fs::write(dir.join("src/lib.rs"), UNFORMATTED).unwrap();
+    assert!(wait_formatted(&dir.join("src/lib.rs")));
+    fs::write(dir.join("src/new.rs"), UNFORMATTED).unwrap();
+    assert!(wait_formatted(&dir.join("src/new.rs")));
+}
+
+#[test]
+fn watch_follows_new_modules() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    fixture(dir);
+    let _watch = watch(dir, &["--follow-modules"]);
+
+    // File added before the declaration
+    fs::write(dir.join("src/stray.rs"), UNFORMATTED).unwrap();
+    fs::write(dir.join("src/a.rs"), UNFORMATTED).unwrap();
+    fs::write(dir.join("src/lib.rs"), format!("mod a;\n{}", UNFORMATTED)).unwrap();
+    assert!(wait_formatted(&dir.join("src/a.rs")));
+    assert!(is_formatted(&dir.join("src/lib.rs")));
+
+    // Declaration added before the file, in a new directory
+    fs::write(dir.join("src/a.rs"), format!("mod b;\n{}", UNFORMATTED)).unwrap();
+    assert!(wait_formatted(&dir.join("src/a.rs")));
+    fs::create_dir_all(dir.join("src/a")).unwrap();
+    fs::write(dir.join("src/a/b.rs"), UNFORMATTED).unwrap();
+    assert!(wait_formatted(&dir.join("src/a/b.rs")));
+
+    // Files that aren't modules still aren't formatted
+    assert!(!is_formatted(&dir.join("src/stray.rs")));
+}
@@ -0,0 +1,62 @@
+use {
+    common::{
+        run_ok,
+        FORMATTED,
+        UNFORMATTED,
+    },
+    std::{
+        fs,
+        path::Path,
+    },
+};
+
+mod common;
+
+/// Names of all files in `dir`.
+fn files(dir: &Path) -> Vec<String> {
+    let mut out =
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+    out.sort();
+    out
+}
+
+#[test]
+fn backup_and_no_temp_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("a.rs"), UNFORMATTED).unwrap();
+    fs::write(dir.join("b.rs"), FORMATTED).unwrap();
+    run_ok(dir, &["--backup", ".orig", "a.rs", "b.rs"]);
+    assert_eq!(fs::read_to_string(dir.join("a.rs")).unwrap(), FORMATTED);
+    assert_eq!(fs::read_to_string(dir.join("a.rs.orig")).unwrap(), UNFORMATTED);
+
+    // Unchanged files aren't written or backed up, and no temporary files are left
+    assert_eq!(files(dir), vec!["a.rs", "a.rs.orig", "b.rs"]);
+}
+
+#[test]
+fn no_backup_by_default() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("a.rs"), UNFORMATTED).unwrap();
+    run_ok(dir, &["a.rs"]);
+    assert_eq!(fs::read_to_string(dir.join("a.rs")).unwrap(), FORMATTED);
+    assert_eq!(files(dir), vec!["a.rs"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn permissions_kept() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("a.rs"), UNFORMATTED).unwrap();
+    fs::set_permissions(dir.join("a.rs"), fs::Permissions::from_mode(0o640)).unwrap();
+    run_ok(dir, &["a.rs"]);
+    assert_eq!(fs::read_to_string(dir.join("a.rs")).unwrap(), FORMATTED);
+    assert_eq!(fs::metadata(dir.join("a.rs")).unwrap().permissions().mode() & 0o777, 0o640);
+}